@@ -0,0 +1,210 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use crate::error::Result;
+use crate::services::{StorageBackend, backend_for};
+use crate::settings::{SYNC_SETTINGS_FILE, load_or_create_sync_settings};
+use crate::SyncSettings;
+
+/// What the sync loop keeps between runs: each folder's `sync.json`, re-read only
+/// when the file changes, and the backends built from them, so clients, TLS sessions
+/// and credentials are set up once rather than on every scan.
+pub struct SyncEngine {
+    folders: HashMap<String, Folder>,
+    /// Folders with the same connection settings share a backend.
+    backends: HashMap<ConnectionKey, Arc<dyn StorageBackend>>,
+}
+
+struct Folder {
+    sync_settings: Arc<SyncSettings>,
+    /// When `sync.json` was last modified as of reading it.
+    settings_modified: Option<SystemTime>,
+}
+
+/// Every setting `backend_for` reads. Backends are reused for folders whose settings
+/// all match.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ConnectionKey {
+    service: String,
+    access_key: String,
+    secret_key: String,
+    region: String,
+    bucket: String,
+    endpoint: String,
+    public: bool,
+    port: u16,
+    private_key: String,
+    known_hosts: String,
+    tls: String,
+    sas_token: String,
+    access_tier: String,
+    credentials_file: String,
+    force_path_style: bool,
+    part_concurrency: usize,
+}
+
+impl ConnectionKey {
+    fn new(sync_settings: &SyncSettings) -> Self {
+        ConnectionKey {
+            service: sync_settings.service.clone(),
+            access_key: sync_settings.access_key.clone(),
+            secret_key: sync_settings.secret_key.clone(),
+            region: sync_settings.region.clone(),
+            bucket: sync_settings.bucket.clone(),
+            endpoint: sync_settings.endpoint.clone(),
+            public: sync_settings.public,
+            port: sync_settings.port,
+            private_key: sync_settings.private_key.clone(),
+            known_hosts: sync_settings.known_hosts.clone(),
+            tls: sync_settings.tls.clone(),
+            sas_token: sync_settings.sas_token.clone(),
+            access_tier: sync_settings.access_tier.clone(),
+            credentials_file: sync_settings.credentials_file.clone(),
+            force_path_style: sync_settings.force_path_style,
+            part_concurrency: sync_settings.part_concurrency,
+        }
+    }
+}
+
+impl SyncEngine {
+    pub fn new() -> Self {
+        SyncEngine {
+            folders: HashMap::new(),
+            backends: HashMap::new(),
+        }
+    }
+
+    /// Returns a folder's sync settings, reading `sync.json` again only if it has
+    /// been modified since the last time. Fails if it can't be read or created.
+    pub fn sync_settings(&mut self, dir: &str) -> Result<Arc<SyncSettings>> {
+        let modified = settings_modified(dir);
+        if let Some(folder) = self.folders.get(dir) {
+            if modified.is_some() && folder.settings_modified == modified {
+                return Ok(folder.sync_settings.clone());
+            }
+        }
+
+        let sync_settings = Arc::new(load_or_create_sync_settings(dir)?);
+        self.folders.insert(dir.to_string(), Folder {
+            sync_settings: sync_settings.clone(),
+            // Loading creates the file if it was missing, so check again
+            settings_modified: settings_modified(dir),
+        });
+        self.drop_unused_backends();
+        Ok(sync_settings)
+    }
+
+    /// Returns the backend for a folder's settings, creating it the first time or if
+    /// the one from an earlier sync has lost its connection. The engine is only locked
+    /// to look the backend up and to store it: checking the connection and connecting
+    /// can take a while (an FTP check waits out a transfer in progress), and folders
+    /// on other backends shouldn't wait for that.
+    pub async fn backend(engine: &Mutex<SyncEngine>, sync_settings: &SyncSettings) -> Result<Arc<dyn StorageBackend>> {
+        let key = ConnectionKey::new(sync_settings);
+        let cached = engine.lock().await.backends.get(&key).cloned();
+        if let Some(backend) = cached {
+            if backend.still_connected().await {
+                return Ok(backend);
+            }
+        }
+
+        let backend: Arc<dyn StorageBackend> = Arc::from(backend_for(sync_settings).await?);
+        engine.lock().await.backends.insert(key, backend.clone());
+        Ok(backend)
+    }
+
+    /// Drops backends no folder's settings point at any more, closing their
+    /// connections.
+    fn drop_unused_backends(&mut self) {
+        let in_use: HashSet<ConnectionKey> = self.folders.values()
+            .map(|folder| ConnectionKey::new(&folder.sync_settings))
+            .collect();
+        self.backends.retain(|key, _| in_use.contains(key));
+    }
+}
+
+fn settings_modified(dir: &str) -> Option<SystemTime> {
+    fs::metadata(Path::new(dir).join(SYNC_SETTINGS_FILE)).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::testing::{sync_settings, temp_dir};
+
+    /// A synced folder whose `sync.json` mirrors into `destination`.
+    fn folder(name: &str, destination: &str) -> String {
+        let dir = temp_dir(name);
+        write_sync_settings(&dir.to_string_lossy(), destination, SystemTime::now());
+        dir.to_string_lossy().to_string()
+    }
+
+    fn write_sync_settings(dir: &str, destination: &str, modified: SystemTime) {
+        let path = Path::new(dir).join(SYNC_SETTINGS_FILE);
+        fs::write(&path, serde_json::to_string(&sync_settings("local", destination)).unwrap()).unwrap();
+        fs::File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn connection_key_ignores_settings_backends_dont_read() {
+        let settings = sync_settings("s3", "bucket");
+        let mut other = sync_settings("s3", "bucket");
+        other.sync_type = "upload-and-download".to_string();
+        other.prefix = "photos".to_string();
+        other.max_depth = 1;
+        other.max_concurrent_transfers = 1;
+        assert!(ConnectionKey::new(&settings) == ConnectionKey::new(&other));
+
+        let changes: [fn(&mut SyncSettings); 5] = [
+            |settings| settings.bucket = "other".to_string(),
+            |settings| settings.secret_key = "rotated".to_string(),
+            |settings| settings.endpoint = "https://example.com".to_string(),
+            |settings| settings.public = true,
+            |settings| settings.part_concurrency = 1,
+        ];
+        for change in changes {
+            let mut changed = sync_settings("s3", "bucket");
+            change(&mut changed);
+            assert!(ConnectionKey::new(&settings) != ConnectionKey::new(&changed));
+        }
+    }
+
+    #[test]
+    fn sync_settings_are_read_again_once_changed() {
+        let dir = folder("engine-settings", "first");
+        let mut engine = SyncEngine::new();
+        let first = engine.sync_settings(&dir).unwrap();
+        assert_eq!(first.bucket, "first");
+        assert!(Arc::ptr_eq(&first, &engine.sync_settings(&dir).unwrap()));
+
+        write_sync_settings(&dir, "second", SystemTime::now() + Duration::from_secs(10));
+        assert_eq!(engine.sync_settings(&dir).unwrap().bucket, "second");
+    }
+
+    #[tokio::test]
+    async fn backends_are_shared_and_dropped_once_unused() {
+        let (first_dir, second_dir) = (folder("engine-backend-1", "destination"), folder("engine-backend-2", "destination"));
+        let engine = Mutex::new(SyncEngine::new());
+        let first_settings = engine.lock().await.sync_settings(&first_dir).unwrap();
+        let second_settings = engine.lock().await.sync_settings(&second_dir).unwrap();
+
+        let first = SyncEngine::backend(&engine, &first_settings).await.unwrap();
+        let second = SyncEngine::backend(&engine, &second_settings).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // Still used by the second folder
+        write_sync_settings(&first_dir, "elsewhere", SystemTime::now() + Duration::from_secs(10));
+        engine.lock().await.sync_settings(&first_dir).unwrap();
+        assert_eq!(engine.lock().await.backends.len(), 1);
+
+        write_sync_settings(&second_dir, "elsewhere", SystemTime::now() + Duration::from_secs(10));
+        let second_settings = engine.lock().await.sync_settings(&second_dir).unwrap();
+        assert_eq!(engine.lock().await.backends.len(), 0);
+        let moved = SyncEngine::backend(&engine, &second_settings).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &moved));
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::io;
+
+/// Why a sync, or part of one, failed. Each message says what was being done, e.g.
+/// `Uploading photos/a.jpg: ...`.
+#[derive(Debug)]
+pub enum IceBucketError {
+    /// The folder's settings can't work, e.g. an unreadable key file or a bad endpoint.
+    Config(String),
+    /// Reading or writing a local file failed.
+    Io { path: String, source: io::Error },
+    /// The service rejected the credentials, or they don't allow what was asked.
+    Auth(String),
+    /// The service couldn't be reached, or the connection dropped.
+    Network(String),
+    /// The service asked for requests to slow down.
+    Throttled(String),
+    /// The service failed on its end, e.g. with a 500. Usually passes.
+    Server(String),
+    /// The object or folder doesn't exist.
+    NotFound(String),
+    /// The service refused the request for some other reason.
+    Service(String),
+}
+
+pub type Result<T> = std::result::Result<T, IceBucketError>;
+
+impl IceBucketError {
+    pub fn io(path: impl fmt::Display, source: io::Error) -> Self {
+        IceBucketError::Io { path: path.to_string(), source }
+    }
+
+    /// Classifies a failed HTTP response by its status code.
+    pub fn from_status(status: u16, message: String) -> Self {
+        match status {
+            401 | 403 => IceBucketError::Auth(message),
+            404 => IceBucketError::NotFound(message),
+            429 | 503 => IceBucketError::Throttled(message),
+            500..=599 => IceBucketError::Server(message),
+            _ => IceBucketError::Service(message),
+        }
+    }
+
+    /// Whether the failure is down to the one file or object being synced, so the
+    /// rest of the folder can carry on. The others would fail every file in turn.
+    pub fn is_per_file(&self) -> bool {
+        matches!(self, IceBucketError::Io { .. } | IceBucketError::NotFound(_) | IceBucketError::Server(_) | IceBucketError::Service(_))
+    }
+
+    /// Whether trying the same thing again a little later might work. Anything else,
+    /// such as rejected credentials or a missing bucket, fails the same way every time.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, IceBucketError::Network(_) | IceBucketError::Throttled(_) | IceBucketError::Server(_))
+    }
+}
+
+impl fmt::Display for IceBucketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IceBucketError::Config(message) => write!(f, "Invalid settings: {}", message),
+            IceBucketError::Io { path, source } => write!(f, "{}: {}", path, source),
+            IceBucketError::Auth(message) => write!(f, "Not authorized: {}", message),
+            IceBucketError::Network(message) => write!(f, "Network error: {}", message),
+            IceBucketError::Throttled(message) => write!(f, "Throttled: {}", message),
+            IceBucketError::Server(message) => write!(f, "Service unavailable: {}", message),
+            IceBucketError::NotFound(message) => write!(f, "Not found: {}", message),
+            IceBucketError::Service(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for IceBucketError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IceBucketError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// For the HTTP-based backends. Errors carrying a status came from the service;
+/// the rest never got a usable response.
+impl From<reqwest::Error> for IceBucketError {
+    fn from(e: reqwest::Error) -> Self {
+        match e.status() {
+            Some(status) => IceBucketError::from_status(status.as_u16(), e.to_string()),
+            None if e.is_decode() => IceBucketError::Service(e.to_string()),
+            None => IceBucketError::Network(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message() -> String {
+        "Uploading a.txt".to_string()
+    }
+
+    #[test]
+    fn from_status_classifies_responses() {
+        assert!(matches!(IceBucketError::from_status(401, message()), IceBucketError::Auth(_)));
+        assert!(matches!(IceBucketError::from_status(403, message()), IceBucketError::Auth(_)));
+        assert!(matches!(IceBucketError::from_status(404, message()), IceBucketError::NotFound(_)));
+        assert!(matches!(IceBucketError::from_status(429, message()), IceBucketError::Throttled(_)));
+        assert!(matches!(IceBucketError::from_status(503, message()), IceBucketError::Throttled(_)));
+        assert!(matches!(IceBucketError::from_status(500, message()), IceBucketError::Server(_)));
+        assert!(matches!(IceBucketError::from_status(504, message()), IceBucketError::Server(_)));
+        assert!(matches!(IceBucketError::from_status(400, message()), IceBucketError::Service(_)));
+        assert!(matches!(IceBucketError::from_status(409, message()), IceBucketError::Service(_)));
+    }
+
+    #[test]
+    fn only_passing_failures_are_transient() {
+        assert!(IceBucketError::Network(message()).is_transient());
+        assert!(IceBucketError::Throttled(message()).is_transient());
+        assert!(IceBucketError::Server(message()).is_transient());
+        assert!(!IceBucketError::Config(message()).is_transient());
+        assert!(!IceBucketError::Auth(message()).is_transient());
+        assert!(!IceBucketError::NotFound(message()).is_transient());
+        assert!(!IceBucketError::Service(message()).is_transient());
+        assert!(!IceBucketError::io("a.txt", io::Error::from(io::ErrorKind::PermissionDenied)).is_transient());
+    }
+
+    #[test]
+    fn folder_wide_failures_are_not_per_file() {
+        assert!(IceBucketError::io("a.txt", io::Error::from(io::ErrorKind::PermissionDenied)).is_per_file());
+        assert!(IceBucketError::NotFound(message()).is_per_file());
+        assert!(IceBucketError::Server(message()).is_per_file());
+        assert!(IceBucketError::Service(message()).is_per_file());
+        assert!(!IceBucketError::Config(message()).is_per_file());
+        assert!(!IceBucketError::Auth(message()).is_per_file());
+        assert!(!IceBucketError::Network(message()).is_per_file());
+        assert!(!IceBucketError::Throttled(message()).is_per_file());
+    }
+}
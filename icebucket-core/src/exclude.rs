@@ -0,0 +1,181 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const IGNORE_FILE: &str = ".icebucketignore";
+const GITIGNORE_FILE: &str = ".gitignore";
+
+/// Decides which files are left out of a sync, using gitignore semantics: the global
+/// `ignore_patterns` from settings.json, plus any `.icebucketignore` (and, if enabled,
+/// `.gitignore`) file in the synced folder or its subfolders. As in git, patterns in
+/// deeper files take precedence, and `.icebucketignore` wins over `.gitignore`.
+///
+/// Everything is expressed in object keys (`/`-separated paths relative to the synced
+/// folder), so remote objects can be checked the same way as local files.
+pub struct ExcludeRules {
+    root: PathBuf,
+    global: Gitignore,
+    use_gitignore: bool,
+    /// Parsed ignore files, by the key of the folder they are in (`""` for the root).
+    folders: HashMap<String, Vec<Gitignore>>,
+}
+
+impl ExcludeRules {
+    pub fn new(root: &Path, global_patterns: &[String], use_gitignore: bool) -> Self {
+        let mut builder = GitignoreBuilder::new(matcher_root(root));
+        for pattern in global_patterns {
+            if let Err(e) = builder.add_line(None, pattern) {
+                eprintln!("Invalid ignore pattern {:?}: {}", pattern, e);
+            }
+        }
+        let global = builder.build().unwrap_or_else(|e| {
+            eprintln!("Failed to build ignore patterns: {}", e);
+            Gitignore::empty()
+        });
+        ExcludeRules {
+            root: root.to_path_buf(),
+            global,
+            use_gitignore,
+            folders: HashMap::new(),
+        }
+    }
+
+    /// Whether `key` is excluded, either itself or because one of its parent folders is.
+    pub fn is_excluded(&mut self, key: &str, is_dir: bool) -> bool {
+        let parts: Vec<&str> = key.split('/').collect();
+        (1..=parts.len()).any(|end| {
+            let is_last = end == parts.len();
+            self.matches(&parts[..end], !is_last || is_dir)
+        })
+    }
+
+    /// Like `is_excluded`, for use during a walk where the parent folders of `key`
+    /// have already been checked.
+    pub fn is_entry_excluded(&mut self, key: &str, is_dir: bool) -> bool {
+        let parts: Vec<&str> = key.split('/').collect();
+        self.matches(&parts, is_dir)
+    }
+
+    fn matches(&mut self, parts: &[&str], is_dir: bool) -> bool {
+        for depth in (0..parts.len()).rev() {
+            let folder = parts[..depth].join("/");
+            let relative: PathBuf = parts[depth..].iter().collect();
+            for matcher in self.matchers_for(&folder).iter().rev() {
+                match matcher.matched(&relative, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => {}
+                }
+            }
+        }
+        let relative: PathBuf = parts.iter().collect();
+        self.global.matched(&relative, is_dir).is_ignore()
+    }
+
+    fn matchers_for(&mut self, folder: &str) -> &Vec<Gitignore> {
+        if !self.folders.contains_key(folder) {
+            let dir = folder.split('/').filter(|part| !part.is_empty()).fold(self.root.clone(), |dir, part| dir.join(part));
+            let mut names = Vec::new();
+            if self.use_gitignore {
+                names.push(GITIGNORE_FILE);
+            }
+            names.push(IGNORE_FILE);
+
+            let matchers = names.into_iter()
+                .filter_map(|name| load_ignore_file(&dir, name))
+                .collect();
+            self.folders.insert(folder.to_string(), matchers);
+        }
+        &self.folders[folder]
+    }
+}
+
+fn load_ignore_file(dir: &Path, name: &str) -> Option<Gitignore> {
+    let path = dir.join(name);
+    if !path.is_file() {
+        return None;
+    }
+    let mut builder = GitignoreBuilder::new(matcher_root(dir));
+    if let Some(e) = builder.add(&path) {
+        eprintln!("Problem reading {}: {}", path.display(), e);
+    }
+    match builder.build() {
+        Ok(matcher) => Some(matcher),
+        Err(e) => {
+            eprintln!("Failed to parse {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Matchers are only ever given relative paths. Rooting them at an absolute path
+/// keeps the `ignore` crate from stripping a leading folder that happens to share
+/// the root's name.
+fn matcher_root(dir: &Path) -> PathBuf {
+    fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    fn rules(name: &str, patterns: &[&str]) -> ExcludeRules {
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        ExcludeRules::new(&temp_dir(name), &patterns, false)
+    }
+
+    #[test]
+    fn negated_patterns_take_files_back() {
+        let mut rules = rules("exclude-negation", &["*.log", "!keep.log"]);
+        assert!(rules.is_excluded("debug.log", false));
+        assert!(rules.is_excluded("logs/debug.log", false));
+        assert!(!rules.is_excluded("keep.log", false));
+        assert!(!rules.is_excluded("logs/keep.log", false));
+    }
+
+    #[test]
+    fn double_star_matches_any_depth() {
+        let mut rules = rules("exclude-double-star", &["build/**", "**/cache", "docs/**/*.tmp"]);
+        assert!(rules.is_excluded("build/a.o", false));
+        assert!(rules.is_excluded("build/x/y/a.o", false));
+        assert!(rules.is_excluded("cache/a", false));
+        assert!(rules.is_excluded("a/b/cache/c", false));
+        assert!(rules.is_excluded("docs/a.tmp", false));
+        assert!(rules.is_excluded("docs/x/y/a.tmp", false));
+        assert!(!rules.is_excluded("src/build.rs", false));
+        assert!(!rules.is_excluded("notes/a.tmp", false));
+    }
+
+    #[test]
+    fn directory_patterns_only_match_folders() {
+        let mut rules = rules("exclude-directory", &["tmp/"]);
+        assert!(rules.is_excluded("tmp", true));
+        assert!(!rules.is_excluded("tmp", false));
+        assert!(rules.is_excluded("tmp/a.txt", false));
+        assert!(rules.is_excluded("a/tmp/b.txt", false));
+        assert!(rules.is_entry_excluded("a/tmp", true));
+        assert!(!rules.is_entry_excluded("a/tmp", false));
+    }
+
+    #[test]
+    fn deeper_ignore_files_take_precedence() {
+        let root = temp_dir("exclude-files");
+        fs::write(root.join(IGNORE_FILE), "secret.txt\n").unwrap();
+        fs::create_dir(root.join("shared")).unwrap();
+        fs::write(root.join("shared").join(IGNORE_FILE), "!important.log\n").unwrap();
+        fs::write(root.join(GITIGNORE_FILE), "*.bak\n").unwrap();
+
+        let mut rules = ExcludeRules::new(&root, &["*.log".to_string()], false);
+        assert!(rules.is_excluded("secret.txt", false));
+        assert!(rules.is_excluded("shared/secret.txt", false));
+        assert!(rules.is_excluded("debug.log", false));
+        assert!(!rules.is_excluded("shared/important.log", false));
+        assert!(!rules.is_excluded("a.bak", false));
+
+        let mut rules = ExcludeRules::new(&root, &[], true);
+        assert!(rules.is_excluded("a.bak", false));
+    }
+}
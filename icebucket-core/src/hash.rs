@@ -0,0 +1,86 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use crate::state::{FileState, now_secs};
+
+/// Object metadata key the SHA-256 of the uploaded content is stored under.
+pub const HASH_METADATA_KEY: &str = "sha256";
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Streams the file through SHA-256 and returns the lowercase hex digest.
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    digest_file::<Sha256>(path)
+}
+
+/// Streams the file through MD5 and returns the lowercase hex digest, which is what
+/// S3 reports as the ETag of an object uploaded in one piece.
+pub fn md5_file(path: &Path) -> io::Result<String> {
+    digest_file::<Md5>(path)
+}
+
+fn digest_file<D: Digest>(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = D::new();
+    let mut buffer = vec![0; HASH_BUFFER_SIZE];
+    loop {
+        let bytes_read = file.read(&mut buffer)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Returns the hash recorded in `known` if the file's size and modification time still
+/// match it and it isn't older than `seconds_between_rehashes`. The periodic rehash is
+/// what catches edits made by tools that preserve the original modification time.
+pub fn cached_hash(known: Option<&FileState>, size: u64, modified: u64, seconds_between_rehashes: u64) -> Option<String> {
+    let known = known?;
+    if known.size != size || known.modified != modified {
+        return None;
+    }
+    if now_secs().saturating_sub(known.hashed_at) >= seconds_between_rehashes {
+        return None;
+    }
+    known.hash.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn hash_file_matches_known_digest() {
+        let path = temp_dir("hash-file").join("a.txt");
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(hash_file(&path).unwrap(), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(md5_file(&path).unwrap(), "900150983cd24fb0d6963f7d28e17f72");
+    }
+
+    #[test]
+    fn cached_hash_is_dropped_when_the_file_may_have_changed() {
+        let known = FileState {
+            size: 5,
+            modified: 1000,
+            hash: Some("hash".to_string()),
+            hashed_at: now_secs(),
+            ..FileState::default()
+        };
+        assert_eq!(cached_hash(Some(&known), 5, 1000, 3600), Some("hash".to_string()));
+        assert_eq!(cached_hash(None, 5, 1000, 3600), None);
+        assert_eq!(cached_hash(Some(&known), 6, 1000, 3600), None);
+        assert_eq!(cached_hash(Some(&known), 5, 1001, 3600), None);
+
+        let stale = FileState { hashed_at: now_secs() - 7200, ..known.clone() };
+        assert_eq!(cached_hash(Some(&stale), 5, 1000, 3600), None);
+        assert_eq!(cached_hash(Some(&stale), 5, 1000, 86400), Some("hash".to_string()));
+
+        let unhashed = FileState { hash: None, ..known };
+        assert_eq!(cached_hash(Some(&unhashed), 5, 1000, 3600), None);
+    }
+}
@@ -1,6 +1,9 @@
 use serde::{Serialize, Deserialize};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::fs;
+use std::sync::Mutex;
+
+pub const LOG_FILE: &str = "sync.log";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LogEntry {
@@ -11,6 +14,9 @@ pub struct LogEntry {
     pub total_bytes: u64,
     pub completed: bool,
     pub updated_at: u64,
+    /// Why the transfer failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 impl LogEntry {
@@ -23,38 +29,74 @@ impl LogEntry {
             total_bytes,
             completed: false,
             updated_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            error: None,
         }
     }
 }
 
+/// Shared by every transfer in flight, so entries sit behind a lock.
 pub struct Log {
-    pub entries: Vec<LogEntry>,
+    entries: Mutex<Vec<LogEntry>>,
 }
 
 impl Log {
     pub fn new() -> Self {
-        Log { entries: Vec::new() }
+        Log { entries: Mutex::new(Vec::new()) }
+    }
+
+    pub fn add_entry(&self, entry: LogEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        // A retry takes the place of what's left of the attempt before it
+        entries.retain(|e| e.completed || e.file_path != entry.file_path);
+        entries.push(entry);
+        write_to_file(&entries);
     }
 
-    pub fn add_entry(&mut self, entry: LogEntry) {
-        self.entries.push(entry);
-        self.write_to_file();
+    /// Counts more bytes of a transfer as done, without marking it completed.
+    pub fn add_progress(&self, file_path: &str, bytes: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.iter_mut().rev().find(|e| e.file_path == file_path) {
+            entry.completed_bytes += bytes;
+        }
     }
 
-    pub fn update_entry(&mut self, file_path: &str, completed_bytes: u64) {
-        if let Some(entry) = self.entries.iter_mut().find(|e| e.file_path == file_path) {
+    pub fn update_entry(&self, file_path: &str, completed_bytes: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        // The latest entry, as an earlier attempt may have failed
+        if let Some(entry) = entries.iter_mut().rev().find(|e| e.file_path == file_path) {
             entry.completed_bytes = completed_bytes;
             entry.completed = true;
             entry.updated_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
         }
-        self.write_to_file();
+        write_to_file(&entries);
     }
 
-    fn write_to_file(&self) {
-        let mut sorted_entries = self.entries.clone();
-        sorted_entries.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
-        let recent_entries: Vec<LogEntry> = sorted_entries.into_iter().take(4).collect();
-        let log_content = serde_json::to_string(&recent_entries).expect("Failed to serialize log entries");
-        fs::write("sync.log", log_content).expect("Unable to write to sync.log");
+    /// Records why syncing a file failed, on its unfinished entry if the transfer got
+    /// that far, otherwise on a new one.
+    pub fn record_failure(&self, file_path: &str, destination_service: &str, destination_bucket: &str, error: String) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.iter_mut().rev().find(|e| e.file_path == file_path && !e.completed) {
+            Some(entry) => {
+                entry.error = Some(error);
+                entry.updated_at = now;
+            }
+            None => {
+                let mut entry = LogEntry::new(file_path.to_string(), destination_service.to_string(), destination_bucket.to_string(), 0);
+                entry.error = Some(error);
+                entries.push(entry);
+            }
+        }
+        write_to_file(&entries);
+    }
+}
+
+fn write_to_file(entries: &[LogEntry]) {
+    let mut sorted_entries = entries.to_vec();
+    sorted_entries.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+    let recent_entries: Vec<LogEntry> = sorted_entries.into_iter().take(4).collect();
+    let log_content = serde_json::to_string(&recent_entries).expect("Failed to serialize log entries");
+    if let Err(e) = fs::write(LOG_FILE, log_content) {
+        eprintln!("Unable to write to {}: {}", LOG_FILE, e);
     }
 }
@@ -1,5 +1,5 @@
 use std::env;
-use std::{thread, time::Duration, process::Command};
+use std::{thread, time::{Duration, Instant}, process::Command};
 use trayicon::{Icon, MenuBuilder, MenuItem, TrayIcon, TrayIconBuilder};
 use winit::{
     application::ApplicationHandler,
@@ -8,20 +8,37 @@ use winit::{
     window::WindowId,
 };
 use windows::Win32::System::Console::FreeConsole;
+use windows::Win32::UI::WindowsAndMessaging::{MessageBoxW, IDYES, MB_ICONWARNING, MB_YESNO};
+use windows::core::HSTRING;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::SystemTime;
 use tokio::runtime::Runtime;
+use tokio::sync::Mutex;
 use sysinfo::System;
+mod engine;
+mod error;
+mod exclude;
+mod hash;
 mod install;
+mod scheduler;
 mod services;
 mod settings;
+mod state;
 mod sync;
 mod logger;
+#[cfg(test)]
+mod testing;
+mod watch;
+use engine::SyncEngine;
 use logger::Log;
+use scheduler::TransferScheduler;
 use settings::load_or_create_settings;
-use sync::sync_directory;
+use state::{SyncState, now_secs};
+use sync::{SyncScope, remove_exposed_sync_settings, sync_directory};
+use watch::DirectoryWatcher;
 
 // This program is a simple file sync tool that runs in the system tray.
 // It scans specified directories for files and syncs the changes to
@@ -39,6 +56,18 @@ enum UserEvents {
 struct Settings {
     directories_to_scan: Vec<String>,
     seconds_between_scans: u64,
+    #[serde(default = "settings::default_state_directory")]
+    state_directory: String,
+    #[serde(default)]
+    ignore_patterns: Vec<String>,
+    #[serde(default = "settings::default_temp_file_patterns")]
+    temp_file_patterns: Vec<String>,
+    #[serde(default = "settings::default_watch_for_changes")]
+    watch_for_changes: bool,
+    #[serde(default = "settings::default_debounce_milliseconds")]
+    debounce_milliseconds: u64,
+    #[serde(default = "settings::default_max_concurrent_transfers")]
+    max_concurrent_transfers: usize,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -51,7 +80,42 @@ struct SyncSettings {
     endpoint: String,
     sync_type: String,
     conflicts: String,
+    #[serde(default)]
     public: bool,
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    deletion_policy: String,
+    #[serde(default = "settings::default_trash_retention_days")]
+    trash_retention_days: u64,
+    #[serde(default = "settings::default_max_depth")]
+    max_depth: usize,
+    #[serde(default = "settings::default_seconds_between_rehashes")]
+    seconds_between_rehashes: u64,
+    #[serde(default)]
+    use_gitignore: bool,
+    #[serde(default = "settings::default_seconds_until_stable")]
+    seconds_until_stable: u64,
+    #[serde(default)]
+    port: u16,
+    #[serde(default)]
+    private_key: String,
+    #[serde(default)]
+    known_hosts: String,
+    #[serde(default)]
+    tls: String,
+    #[serde(default)]
+    sas_token: String,
+    #[serde(default)]
+    access_tier: String,
+    #[serde(default)]
+    credentials_file: String,
+    #[serde(default)]
+    force_path_style: bool,
+    #[serde(default = "settings::default_folder_concurrent_transfers")]
+    max_concurrent_transfers: usize,
+    #[serde(default = "settings::default_part_concurrency")]
+    part_concurrency: usize,
 }
 
 static VERBOSE: AtomicBool = AtomicBool::new(false);
@@ -115,21 +179,68 @@ fn main() {
     thread::spawn(move || {
         let rt = Runtime::new().unwrap();
         rt.block_on(async {
-            let mut file_maps: HashMap<String, HashMap<String, SystemTime>> = HashMap::new();
-            let mut log = Log::new(); // Initialize the log
+            let mut sync_states: HashMap<String, SyncState> = HashMap::new();
+            let log = Arc::new(Log::new()); // Initialize the log
+            // Folders sync alongside each other, taking turns with the engine
+            let engine = Arc::new(Mutex::new(SyncEngine::new()));
             for dir in &settings.directories_to_scan {
-                file_maps.insert(dir.clone(), HashMap::new());
+                sync_states.insert(dir.clone(), SyncState::load(&settings.state_directory, dir));
+                remove_exposed_sync_settings(dir, &engine).await;
             }
+            let scheduler = Arc::new(TransferScheduler::new(settings.max_concurrent_transfers));
+            let settings = Arc::new(settings);
+            let shared = Shared { settings: settings.clone(), engine, scheduler, log };
+            let watcher = if settings.watch_for_changes {
+                DirectoryWatcher::new(&settings.directories_to_scan)
+            } else {
+                None
+            };
+            let scan_interval = Duration::from_secs(settings.seconds_between_scans);
+            let quiet_period = Duration::from_millis(settings.debounce_milliseconds);
             loop {
-                for dir in &settings.directories_to_scan {
-                    if let Some(file_map) = file_maps.get_mut(dir) {
-                        if VERBOSE.load(Ordering::Relaxed) {
-                            println!("Syncing directory: {}", dir);
+                // Full scans run at startup and then every seconds_between_scans, catching
+                // remote changes and anything the watcher missed
+                let started = now_secs();
+                let scans = sync_states.keys().map(|dir| (dir.clone(), SyncScope::Everything)).collect();
+                sync_folders(&mut sync_states, scans, started, &shared).await;
+
+                // In between, sync whatever the watcher reports as it happens, and retry
+                // files that failed or were still being written once they're due
+                let next_scan = Instant::now() + scan_interval;
+                while Instant::now() < next_scan {
+                    let deadline = sync_states.values()
+                        .flat_map(|sync_state| sync_state.deferred.values().copied().chain(sync_state.next_retry()))
+                        .min()
+                        .map_or(next_scan, |retry_at| retry_at.min(next_scan));
+                    let mut changes = match &watcher {
+                        Some(watcher) => watcher.wait_for_changes(deadline, quiet_period),
+                        None => {
+                            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                            HashMap::new()
+                        }
+                    };
+                    let now = Instant::now();
+                    let retries_due_by = now_secs();
+                    for (dir, sync_state) in sync_states.iter_mut() {
+                        let due: Vec<String> = sync_state.deferred.iter()
+                            .filter(|(_, retry_at)| **retry_at <= now)
+                            .map(|(key, _)| key.clone())
+                            .collect();
+                        for key in due {
+                            sync_state.deferred.remove(&key);
+                            changes.entry(dir.clone()).or_default().insert(Path::new(dir).join(&key));
+                        }
+                        // The empty key joins to the folder itself, which syncs all of it
+                        for key in sync_state.due_retries(retries_due_by) {
+                            changes.entry(dir.clone()).or_default().insert(Path::new(dir).join(&key));
                         }
-                        sync_directory(dir, file_map, &mut log).await; // Pass log to sync_directory
                     }
+
+                    let syncs = changes.into_iter()
+                        .map(|(dir, paths)| (dir, SyncScope::Paths(paths.into_iter().collect())))
+                        .collect();
+                    sync_folders(&mut sync_states, syncs, retries_due_by, &shared).await;
                 }
-                thread::sleep(Duration::from_secs(settings.seconds_between_scans));
             }
         });
     });
@@ -138,6 +249,49 @@ fn main() {
     event_loop.run_app(&mut app).unwrap();
 }
 
+/// What the folders' syncs share: they take turns with the engine and divide the
+/// scheduler's transfer slots between them.
+#[derive(Clone)]
+struct Shared {
+    settings: Arc<Settings>,
+    engine: Arc<Mutex<SyncEngine>>,
+    scheduler: Arc<TransferScheduler>,
+    log: Arc<Log>,
+}
+
+/// Syncs each folder as a task of its own, so one folder's scanning, hashing and
+/// state writes don't hold up the others, and takes their states back once all are
+/// done. Retries that were due by `retries_due_by` have been attempted by then.
+async fn sync_folders(sync_states: &mut HashMap<String, SyncState>, syncs: Vec<(String, SyncScope)>, retries_due_by: u64, shared: &Shared) {
+    let mut tasks = Vec::new();
+    for (dir, scope) in syncs {
+        let Some(mut sync_state) = sync_states.remove(&dir) else { continue };
+        let Shared { settings, engine, scheduler, log } = shared.clone();
+        let folder = dir.clone();
+        tasks.push((dir, tokio::spawn(async move {
+            if VERBOSE.load(Ordering::Relaxed) {
+                match scope {
+                    SyncScope::Everything => println!("Syncing directory: {}", folder),
+                    SyncScope::Paths(_) => println!("Changes in directory: {}", folder),
+                }
+            }
+            sync_directory(&folder, &settings, scope, &engine, &scheduler, &mut sync_state, &log).await;
+            sync_state.forget_due_retries(retries_due_by);
+            sync_state
+        })));
+    }
+    for (dir, task) in tasks {
+        let sync_state = match task.await {
+            Ok(sync_state) => sync_state,
+            Err(e) => {
+                // Whatever it synced before that was saved along the way
+                eprintln!("Syncing {} failed: {}", dir, e);
+                SyncState::load(&shared.settings.state_directory, &dir)
+            }
+        };
+        sync_states.insert(dir, sync_state);
+    }
+}
 
 struct TrayApp {
     tray_icon: TrayIcon<UserEvents>,
@@ -185,4 +339,11 @@ fn is_process_running(process_name: &str) -> bool {
         }
     }
     false
-}
\ No newline at end of file
+}
+
+fn ask_yes_no(caption: &str, text: &str) -> bool {
+    let result = unsafe {
+        MessageBoxW(None, &HSTRING::from(text), &HSTRING::from(caption), MB_YESNO | MB_ICONWARNING)
+    };
+    result == IDYES
+}
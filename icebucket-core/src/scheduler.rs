@@ -0,0 +1,80 @@
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Files larger than this count as large transfers. It's also where uploads switch
+/// to multipart.
+pub const LARGE_FILE_SIZE: u64 = 5 * 1024 * 1024;
+
+/// Limits how many transfers run at once across every synced folder; each folder's
+/// own limit is applied on top by the sync. Large files only get some of the slots,
+/// so a batch of videos can't hold every one while thousands of small files wait.
+pub struct TransferScheduler {
+    all: Semaphore,
+    large: Semaphore,
+}
+
+/// Held for the length of a transfer.
+pub struct TransferSlot<'a> {
+    _large: Option<SemaphorePermit<'a>>,
+    _all: SemaphorePermit<'a>,
+}
+
+impl TransferScheduler {
+    pub fn new(max_concurrent_transfers: usize) -> Self {
+        let max_concurrent_transfers = max_concurrent_transfers.max(1);
+        TransferScheduler {
+            all: Semaphore::new(max_concurrent_transfers),
+            large: Semaphore::new(max_concurrent_transfers.div_ceil(2)),
+        }
+    }
+
+    /// Waits for a slot to transfer `size` bytes in.
+    pub async fn slot(&self, size: u64) -> TransferSlot<'_> {
+        // Large transfers queue for their own slots first, so they don't hold one of
+        // the shared ones while they wait
+        let large = if size > LARGE_FILE_SIZE {
+            Some(self.large.acquire().await.expect("Transfer slots are never closed"))
+        } else {
+            None
+        };
+        TransferSlot {
+            _large: large,
+            _all: self.all.acquire().await.expect("Transfer slots are never closed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    const LARGE: u64 = LARGE_FILE_SIZE + 1;
+
+    #[tokio::test]
+    async fn slots_run_out_at_the_limit() {
+        let scheduler = TransferScheduler::new(3);
+        let mut slots: Vec<_> = (0..3).map(|_| scheduler.slot(1).now_or_never().unwrap()).collect();
+        assert!(scheduler.slot(1).now_or_never().is_none());
+        slots.pop();
+        assert!(scheduler.slot(1).now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn large_files_get_half_the_slots() {
+        let scheduler = TransferScheduler::new(4);
+        let _large: Vec<_> = (0..2).map(|_| scheduler.slot(LARGE).now_or_never().unwrap()).collect();
+        assert!(scheduler.slot(LARGE).now_or_never().is_none());
+        // Small files still get the rest
+        let _small: Vec<_> = (0..2).map(|_| scheduler.slot(LARGE_FILE_SIZE).now_or_never().unwrap()).collect();
+        assert!(scheduler.slot(1).now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn large_files_count_towards_the_limit() {
+        let scheduler = TransferScheduler::new(0);
+        let slot = scheduler.slot(LARGE).now_or_never().unwrap();
+        assert!(scheduler.slot(1).now_or_never().is_none());
+        drop(slot);
+        assert!(scheduler.slot(1).now_or_never().is_some());
+    }
+}
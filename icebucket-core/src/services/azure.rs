@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::{Client, Method, Response, StatusCode, Url};
+use sha2::Sha256;
+use crate::error::{IceBucketError, Result};
+use crate::logger::{LogEntry, Log};
+use crate::{SyncSettings, VERBOSE};
+use super::http::{child_text, header, response_error};
+use super::{RemoteObject, StorageBackend, UploadedObject};
+
+/// Storage service version the requests are written against.
+const API_VERSION: &str = "2023-11-03";
+
+/// Size of each staged block in a large upload.
+const BLOCK_SIZE: usize = 8 * 1024 * 1024; // 8MB
+
+/// The longest wait between checks on a copy that hasn't finished.
+const MAX_COPY_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Characters left as they are in keys and query values; everything else is
+/// percent-encoded.
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+/// Syncs to an Azure Blob Storage container. `access_key` is the storage account name
+/// and `bucket` the container. Requests are signed with the account key in
+/// `secret_key`, or authorized by `sas_token` when one is set.
+///
+/// `endpoint` defaults to the account's public blob endpoint; point it at e.g.
+/// `http://127.0.0.1:10000/devstoreaccount1` to use the Azurite emulator.
+pub struct AzureBackend {
+  http: Client,
+  account: String,
+  /// Scheme, host and port of the endpoint.
+  origin: String,
+  /// Path of the container below the origin, already encoded.
+  container_path: String,
+  auth: Auth,
+  /// Access tier given to uploaded blobs, or `None` for the account's default.
+  access_tier: Option<String>,
+}
+
+enum Auth {
+  SharedKey(Vec<u8>),
+  Sas(String),
+}
+
+impl AzureBackend {
+  /// Fails if the settings can't work.
+  pub fn new(sync_settings: &SyncSettings) -> Result<Self> {
+      let account = sync_settings.access_key.clone();
+      let auth = if !sync_settings.sas_token.is_empty() {
+          Auth::Sas(sync_settings.sas_token.trim_start_matches('?').to_string())
+      } else {
+          let key = BASE64.decode(sync_settings.secret_key.trim())
+              .map_err(|e| IceBucketError::Config(format!("Azure account key for {} isn't valid base64: {}", account, e)))?;
+          Auth::SharedKey(key)
+      };
+
+      let endpoint = if sync_settings.endpoint.is_empty() {
+          format!("https://{}.blob.core.windows.net", account)
+      } else {
+          sync_settings.endpoint.clone()
+      };
+      let endpoint = Url::parse(&endpoint)
+          .map_err(|e| IceBucketError::Config(format!("Invalid Azure endpoint {:?}: {}", endpoint, e)))?;
+      let origin = endpoint.origin().ascii_serialization();
+      let container_path = format!("{}/{}", endpoint.path().trim_end_matches('/'), encode(&sync_settings.bucket));
+
+      Ok(AzureBackend {
+          http: Client::new(),
+          account,
+          origin,
+          container_path,
+          auth,
+          access_tier: Some(sync_settings.access_tier.clone()).filter(|tier| !tier.is_empty()),
+      })
+  }
+
+  /// Sends a request for the container (no `key`) or a blob in it, authorizing it
+  /// with the account key or SAS token.
+  async fn send(&self, method: Method, key: Option<&str>, query: &[(&str, &str)], mut headers: Vec<(String, String)>, body: Vec<u8>) -> Result<Response> {
+      let path = match key {
+          Some(key) => format!("{}/{}", self.container_path, key.split('/').map(encode).collect::<Vec<_>>().join("/")),
+          None => self.container_path.clone(),
+      };
+      headers.push(("x-ms-date".to_string(), httpdate::fmt_http_date(SystemTime::now())));
+      headers.push(("x-ms-version".to_string(), API_VERSION.to_string()));
+
+      let mut query_string = query.iter()
+          .map(|(name, value)| format!("{}={}", name, encode(value)))
+          .collect::<Vec<_>>()
+          .join("&");
+      let mut authorization = None;
+      match &self.auth {
+          Auth::SharedKey(account_key) => {
+              authorization = Some(self.shared_key(account_key, &method, &path, query, &headers, body.len()));
+          }
+          Auth::Sas(token) => {
+              if !query_string.is_empty() {
+                  query_string.push('&');
+              }
+              query_string.push_str(token);
+          }
+      }
+
+      let mut url = format!("{}{}", self.origin, path);
+      if !query_string.is_empty() {
+          url = format!("{}?{}", url, query_string);
+      }
+      let mut request = self.http.request(method, url).body(body);
+      for (name, value) in headers {
+          request = request.header(name, value);
+      }
+      if let Some(authorization) = authorization {
+          request = request.header("Authorization", authorization);
+      }
+      Ok(request.send().await?)
+  }
+
+  /// Builds the `Authorization` header for a Shared Key request. None of the standard
+  /// headers that take part in the signature are sent, apart from the body length.
+  fn shared_key(&self, account_key: &[u8], method: &Method, path: &str, query: &[(&str, &str)], headers: &[(String, String)], content_length: usize) -> String {
+      let mut canonical_headers: Vec<_> = headers.iter()
+          .map(|(name, value)| (name.to_lowercase(), value.trim()))
+          .filter(|(name, _)| name.starts_with("x-ms-"))
+          .collect();
+      canonical_headers.sort();
+      let mut canonical_query: Vec<_> = query.iter().map(|(name, value)| (name.to_lowercase(), *value)).collect();
+      canonical_query.sort();
+
+      let mut string_to_sign = format!(
+          "{}\n\n\n{}\n\n\n\n\n\n\n\n\n",
+          method.as_str(),
+          if content_length == 0 { String::new() } else { content_length.to_string() },
+      );
+      for (name, value) in canonical_headers {
+          string_to_sign.push_str(&format!("{}:{}\n", name, value));
+      }
+      string_to_sign.push_str(&format!("/{}{}", self.account, path));
+      for (name, value) in canonical_query {
+          string_to_sign.push_str(&format!("\n{}:{}", name, value));
+      }
+
+      let mut mac = Hmac::<Sha256>::new_from_slice(account_key).expect("HMAC accepts any key length");
+      mac.update(string_to_sign.as_bytes());
+      format!("SharedKey {}:{}", self.account, BASE64.encode(mac.finalize().into_bytes()))
+  }
+
+  /// Headers that set a blob's metadata, and its access tier when one is configured.
+  fn upload_headers(&self, metadata: &HashMap<String, String>) -> Vec<(String, String)> {
+      let mut headers: Vec<_> = metadata.iter()
+          .map(|(name, value)| (format!("x-ms-meta-{}", name), value.clone()))
+          .collect();
+      if let Some(tier) = &self.access_tier {
+          headers.push(("x-ms-access-tier".to_string(), tier.clone()));
+      }
+      headers
+  }
+
+  fn log_target(&self) -> String {
+      format!("{}{}", self.origin, self.container_path)
+  }
+}
+
+#[async_trait]
+impl StorageBackend for AzureBackend {
+  fn name(&self) -> &'static str {
+      "Azure"
+  }
+
+  /// Lists every blob under `prefix`, following markers past the 5000-blob page limit.
+  async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+      let mut objects = Vec::new();
+      let mut marker = String::new();
+      loop {
+          let mut query = vec![("restype", "container"), ("comp", "list"), ("prefix", prefix), ("include", "metadata")];
+          if !marker.is_empty() {
+              query.push(("marker", marker.as_str()));
+          }
+          let response = self.send(Method::GET, None, &query, Vec::new(), Vec::new()).await?;
+          let body = check(response, "Listing blobs").await?.text().await?;
+          let listing = roxmltree::Document::parse(&body)
+              .map_err(|e| IceBucketError::Service(format!("Unreadable blob listing: {}", e)))?;
+
+          for blob in listing.descendants().filter(|node| node.has_tag_name("Blob")) {
+              let Some(name) = child_text(blob, "Name") else { continue };
+              let properties = blob.children().find(|node| node.has_tag_name("Properties"));
+              let property = |name: &str| properties.and_then(|properties| child_text(properties, name));
+              let metadata = blob.children()
+                  .find(|node| node.has_tag_name("Metadata"))
+                  .map(|metadata| metadata.children()
+                      .filter(|node| node.is_element())
+                      .map(|node| (node.tag_name().name().to_string(), node.text().unwrap_or("").to_string()))
+                      .collect())
+                  .unwrap_or_default();
+              objects.push(RemoteObject {
+                  key: name.to_string(),
+                  size: property("Content-Length").and_then(|size| size.parse().ok()).unwrap_or(0),
+                  e_tag: property("Etag").map(str::to_string),
+                  version_id: None,
+                  last_modified: property("Last-Modified").and_then(|date| httpdate::parse_http_date(date).ok()),
+                  metadata,
+              });
+          }
+
+          marker = child_text(listing.root_element(), "NextMarker").unwrap_or("").to_string();
+          if marker.is_empty() {
+              break;
+          }
+      }
+      Ok(objects)
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<RemoteObject>> {
+      let response = self.send(Method::HEAD, Some(key), &[], Vec::new(), Vec::new()).await?;
+      if blob_not_found(&response) {
+          return Ok(None);
+      }
+      let response = check(response, &format!("Checking blob {}", key)).await?;
+      Ok(Some(remote_object(key, &response)))
+  }
+
+  async fn put(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let body = fs::read(file_path).map_err(|e| IceBucketError::io(file_path, e))?;
+      let total_bytes = body.len() as u64;
+      log.add_entry(LogEntry::new(file_path.to_string(), self.log_target(), key.to_string(), total_bytes));
+
+      let mut headers = self.upload_headers(metadata);
+      headers.push(("x-ms-blob-type".to_string(), "BlockBlob".to_string()));
+      let response = self.send(Method::PUT, Some(key), &[], headers, body).await?;
+      let response = check(response, &format!("Uploading {}", key)).await?;
+
+      log.update_entry(file_path, total_bytes);
+      Ok(UploadedObject {
+          e_tag: header(&response, "etag"),
+          version_id: None,
+      })
+  }
+
+  /// Stages the file as a series of blocks, then commits the block list, which
+  /// is when the blob takes its metadata and access tier.
+  async fn put_multipart(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let file_error = |e| IceBucketError::io(file_path, e);
+      let mut file = fs::File::open(file_path).map_err(file_error)?;
+      let file_size = file.metadata().map_err(file_error)?.len();
+      let num_blocks = file_size.div_ceil(BLOCK_SIZE as u64);
+      if VERBOSE.load(Ordering::Relaxed) {
+          println!("Uploading {} in {} blocks of {} bytes", key, num_blocks, BLOCK_SIZE);
+      }
+      log.add_entry(LogEntry::new(file_path.to_string(), self.log_target(), key.to_string(), file_size));
+
+      let mut block_ids = Vec::new();
+      for block_number in 0..num_blocks {
+          let mut buffer = Vec::with_capacity(BLOCK_SIZE);
+          (&mut file).take(BLOCK_SIZE as u64).read_to_end(&mut buffer).map_err(file_error)?;
+          let bytes_read = buffer.len() as u64;
+
+          // Every block ID in a blob must be the same length
+          let block_id = BASE64.encode(format!("{:08}", block_number));
+          let response = self.send(Method::PUT, Some(key), &[("comp", "block"), ("blockid", &block_id)], Vec::new(), buffer).await?;
+          check(response, &format!("Uploading block {} of {}", block_number + 1, key)).await?;
+          block_ids.push(block_id);
+
+          log.add_progress(file_path, bytes_read);
+      }
+
+      let mut block_list = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>");
+      for block_id in &block_ids {
+          block_list.push_str(&format!("<Latest>{}</Latest>", block_id));
+      }
+      block_list.push_str("</BlockList>");
+      let response = self.send(Method::PUT, Some(key), &[("comp", "blocklist")], self.upload_headers(metadata), block_list.into_bytes()).await?;
+      let response = check(response, &format!("Committing blocks of {}", key)).await?;
+
+      log.update_entry(file_path, file_size);
+      Ok(UploadedObject {
+          e_tag: header(&response, "etag"),
+          version_id: None,
+      })
+  }
+
+  async fn get(&self, key: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject> {
+      let response = self.send(Method::GET, Some(key), &[], Vec::new(), Vec::new()).await?;
+      let mut response = check(response, &format!("Downloading {}", key)).await?;
+      let remote = remote_object(key, &response);
+      let file_path = destination.to_string_lossy().to_string();
+      log.add_entry(LogEntry::new(file_path.clone(), self.log_target(), key.to_string(), remote.size));
+
+      if let Some(parent) = destination.parent() {
+          fs::create_dir_all(parent).map_err(|e| IceBucketError::io(parent.display(), e))?;
+      }
+      let temp_error = |e| IceBucketError::io(temp_path.display(), e);
+      let mut file = fs::File::create(temp_path).map_err(temp_error)?;
+      while let Some(chunk) = response.chunk().await? {
+          file.write_all(&chunk).map_err(temp_error)?;
+          log.add_progress(&file_path, chunk.len() as u64);
+      }
+      file.sync_all().map_err(temp_error)?;
+      drop(file);
+      fs::rename(temp_path, destination).map_err(|e| IceBucketError::io(destination.display(), e))?;
+
+      log.update_entry(&file_path, remote.size);
+      Ok(remote)
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+      let response = self.send(Method::DELETE, Some(key), &[], Vec::new(), Vec::new()).await?;
+      if !blob_not_found(&response) {
+          check(response, &format!("Deleting {}", key)).await?;
+      }
+      Ok(())
+  }
+
+  /// Copies within the container. Large copies may still be pending when the request
+  /// returns, so this waits for the copy to succeed, since the trash deletes the
+  /// source right after.
+  async fn copy(&self, from: &str, to: &str) -> Result<()> {
+      let source = format!(
+          "{}{}/{}",
+          self.origin,
+          self.container_path,
+          from.split('/').map(encode).collect::<Vec<_>>().join("/"),
+      );
+      let source = match &self.auth {
+          Auth::Sas(token) => format!("{}?{}", source, token),
+          Auth::SharedKey(_) => source,
+      };
+      let action = format!("Copying {} to {}", from, to);
+      let response = self.send(Method::PUT, Some(to), &[], vec![("x-ms-copy-source".to_string(), source)], Vec::new()).await?;
+      let mut response = check(response, &action).await?;
+      let mut interval = Duration::from_secs(1);
+      loop {
+          match header(&response, "x-ms-copy-status").as_deref() {
+              Some("success") => return Ok(()),
+              Some("pending") => {}
+              Some(status) => {
+                  let description = header(&response, "x-ms-copy-status-description").unwrap_or_default();
+                  return Err(IceBucketError::Service(format!("{}: copy {} {}", action, status, description)));
+              }
+              None => return Err(IceBucketError::Service(format!("{}: no copy status", action))),
+          }
+          tokio::time::sleep(interval).await;
+          interval = (interval * 2).min(MAX_COPY_POLL_INTERVAL);
+          let head = self.send(Method::HEAD, Some(to), &[], Vec::new(), Vec::new()).await?;
+          response = check(head, &action).await?;
+      }
+  }
+
+  async fn set_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<()> {
+      let headers = metadata.iter()
+          .map(|(name, value)| (format!("x-ms-meta-{}", name), value.clone()))
+          .collect();
+      let response = self.send(Method::PUT, Some(key), &[("comp", "metadata")], headers, Vec::new()).await?;
+      check(response, &format!("Setting metadata on {}", key)).await?;
+      Ok(())
+  }
+}
+
+fn encode(value: &str) -> String {
+  utf8_percent_encode(value, UNRESERVED).to_string()
+}
+
+/// Like `http::check`, but classifies failures by Azure's error code where the status
+/// alone is ambiguous.
+async fn check(response: Response, action: &str) -> Result<Response> {
+  if response.status().is_success() {
+      return Ok(response);
+  }
+  let code = header(&response, "x-ms-error-code");
+  let error = response_error(response, action).await;
+  Err(match code.as_deref() {
+      // Every blob would fail the same way
+      Some("ContainerNotFound" | "AccountIsDisabled") => IceBucketError::Config(error.to_string()),
+      Some("ServerBusy" | "OperationTimedOut") => IceBucketError::Throttled(error.to_string()),
+      _ => error,
+  })
+}
+
+/// A 404 for the blob, rather than for a missing container.
+fn blob_not_found(response: &Response) -> bool {
+  response.status() == StatusCode::NOT_FOUND && header(response, "x-ms-error-code").as_deref() != Some("ContainerNotFound")
+}
+
+
+/// Reads a blob's properties and metadata from the headers of a HEAD or GET.
+fn remote_object(key: &str, response: &Response) -> RemoteObject {
+  let metadata = response.headers().iter()
+      .filter_map(|(name, value)| {
+          let name = name.as_str().strip_prefix("x-ms-meta-")?;
+          Some((name.to_string(), value.to_str().ok()?.to_string()))
+      })
+      .collect();
+  RemoteObject {
+      key: key.to_string(),
+      // HEAD responses have no body, so the length has to come from the header itself
+      size: header(response, "content-length").and_then(|size| size.parse().ok()).unwrap_or(0),
+      e_tag: header(response, "etag"),
+      version_id: None,
+      last_modified: header(response, "last-modified").and_then(|date| httpdate::parse_http_date(&date).ok()),
+      metadata,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::sync_settings;
+
+  fn backend() -> (AzureBackend, Vec<u8>) {
+      let mut settings = sync_settings("azure", "container");
+      settings.access_key = "myaccount".to_string();
+      settings.secret_key = BASE64.encode("key");
+      let backend = AzureBackend::new(&settings).unwrap();
+      (backend, b"key".to_vec())
+  }
+
+  #[test]
+  fn shared_key_signs_canonical_headers_and_query() {
+      let (backend, key) = backend();
+      assert_eq!(backend.container_path, "/container");
+      let headers = vec![
+          ("x-ms-version".to_string(), API_VERSION.to_string()),
+          ("Content-Type".to_string(), "text/plain".to_string()),
+          ("X-MS-Date".to_string(), "Mon, 01 Jan 2024 00:00:00 GMT".to_string()),
+          ("x-ms-meta-mtime".to_string(), " 5 ".to_string()),
+      ];
+      let query = [("comp", "block"), ("blockid", "MDAwMDE=")];
+      assert_eq!(
+          backend.shared_key(&key, &Method::PUT, "/container/a%20b.txt", &query, &headers, 10),
+          "SharedKey myaccount:B0wzbNLc7XHss1qDM5CHUPzkHpZyo9e7r0Xz0cb6/S4=",
+      );
+  }
+
+  #[test]
+  fn shared_key_leaves_out_an_empty_body_length() {
+      let (backend, key) = backend();
+      let headers = vec![("x-ms-version".to_string(), API_VERSION.to_string())];
+      let query = [("restype", "container"), ("comp", "list"), ("prefix", "photos/")];
+      assert_eq!(
+          backend.shared_key(&key, &Method::GET, "/container", &query, &headers, 0),
+          "SharedKey myaccount:lj6o5kR4HKkg4ansPUVuvwaiicOxZVq5phqfd6Faojg=",
+      );
+  }
+
+  #[test]
+  fn new_rejects_a_key_that_isnt_base64() {
+      let mut settings = sync_settings("azure", "container");
+      settings.access_key = "myaccount".to_string();
+      settings.secret_key = "not base64!".to_string();
+      assert!(matches!(AzureBackend::new(&settings), Err(IceBucketError::Config(_))));
+  }
+}
@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use suppaftp::list::{File as ListedFile, ListParser};
+use suppaftp::tokio::{AsyncRustlsConnector, AsyncRustlsFtpStream};
+use suppaftp::tokio_rustls::TlsConnector;
+use suppaftp::tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use suppaftp::tokio_rustls::rustls::crypto::ring;
+use suppaftp::types::FileType;
+use suppaftp::{FtpError, FtpResult, Mode, Status};
+use tokio::io::AsyncSeekExt;
+use tokio::sync::Mutex;
+use crate::error::{IceBucketError, Result};
+use crate::logger::{LogEntry, Log};
+use crate::state::system_time_to_nanos;
+use crate::sync::{TEMP_FILE_SUFFIX, format_date};
+use crate::{SyncSettings, VERBOSE};
+use super::{RemoteObject, StorageBackend, UploadedObject, folder_of, modified_from};
+
+/// Tells apart the local temp files of copies, which may run at the same time on
+/// backends for different servers.
+static COPY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Syncs to a folder on an FTP server. `endpoint` is the host, `access_key` and
+/// `secret_key` the user name and password, and `bucket` the folder on the server that
+/// keys are relative to. `tls` picks explicit FTPS (the default), implicit FTPS or,
+/// for servers that offer nothing else, plain FTP.
+///
+/// Listings use MLSD, so the server must support it (nearly all current ones do). FTP
+/// has no user metadata, so the size and modification time serve as the ETag.
+pub struct FtpBackend {
+  // One control connection carries one transfer at a time
+  ftp: Mutex<AsyncRustlsFtpStream>,
+  host: String,
+  base_path: String,
+  /// The temp file each path's last unfinished upload was written to, so it can be
+  /// removed once a different version of the file is uploaded instead.
+  partials: Mutex<HashMap<String, String>>,
+}
+
+impl FtpBackend {
+  /// Connects and logs in.
+  pub async fn connect(sync_settings: &SyncSettings) -> Result<Self> {
+      let host = sync_settings.endpoint.clone();
+      let ftp = open_connection(sync_settings).await
+          .map_err(|e| ftp_error(format!("Connecting to FTP server {}", host), e))?;
+      let base_path = sync_settings.bucket.trim_end_matches('/').to_string();
+      Ok(FtpBackend {
+          ftp: Mutex::new(ftp),
+          host,
+          base_path: if base_path.is_empty() { ".".to_string() } else { base_path },
+          partials: Mutex::new(HashMap::new()),
+      })
+  }
+
+  fn path_for(&self, key: &str) -> String {
+      format!("{}/{}", self.base_path, key)
+  }
+
+  /// Creates the folders leading up to `key`, like `mkdir -p`.
+  async fn create_parents(&self, ftp: &mut AsyncRustlsFtpStream, key: &str) {
+      let Some((folders, _)) = key.rsplit_once('/') else { return };
+      let mut path = self.base_path.clone();
+      for folder in folders.split('/') {
+          path = format!("{}/{}", path, folder);
+          // MKD is refused for a folder that's already there, which is fine
+          let _ = ftp.mkdir(&path).await;
+      }
+  }
+
+  async fn stat_with(&self, ftp: &mut AsyncRustlsFtpStream, key: &str) -> Result<Option<RemoteObject>> {
+      match ftp.mlst(Some(&self.path_for(key))).await {
+          Ok(line) => {
+              let Ok(file) = ListParser::parse_mlst(&line) else { return Ok(None) };
+              Ok(file.is_file().then(|| remote_object(key, &file)))
+          }
+          Err(e) if is_not_found(&e) => Ok(None),
+          Err(e) => Err(ftp_error(format!("Checking {}", key), e)),
+      }
+  }
+
+  /// Sends the file to a temp file next to `path` and returns the temp file's path.
+  /// The name carries the local file's size and modification time, so an earlier,
+  /// interrupted upload is only picked up where it left off if it was of this same
+  /// version of the file.
+  async fn send_file(&self, ftp: &mut AsyncRustlsFtpStream, file_path: &str, path: &str, total_bytes: u64) -> Result<String> {
+      let mut file = tokio::fs::File::open(file_path).await.map_err(|e| IceBucketError::io(file_path, e))?;
+      let local_modified = fs::metadata(file_path).and_then(|metadata| metadata.modified()).ok();
+      let temp_path = temp_path_for(path, total_bytes, local_modified);
+
+      let stale = self.partials.lock().await.insert(path.to_string(), temp_path.clone());
+      if let Some(stale) = stale.filter(|stale| *stale != temp_path) {
+          let _ = ftp.rm(&stale).await;
+      }
+
+      let partial = match ftp.mlst(Some(&temp_path)).await {
+          Ok(line) => ListParser::parse_mlst(&line).ok(),
+          Err(_) => None,
+      };
+      let offset = match partial {
+          Some(partial) if local_modified.is_some() && (partial.size() as u64) < total_bytes => partial.size() as u64,
+          _ => 0,
+      };
+      if offset > 0 {
+          match ftp.resume_transfer(offset as usize).await {
+              Ok(()) => {
+                  if VERBOSE.load(Ordering::Relaxed) {
+                      println!("Resuming upload of {} at {} of {} bytes", file_path, offset, total_bytes);
+                  }
+                  file.seek(SeekFrom::Start(offset)).await.map_err(|e| IceBucketError::io(file_path, e))?;
+              }
+              Err(e) => eprintln!("Server can't resume the upload of {}, starting over: {}", file_path, e),
+          }
+      }
+
+      let action = || format!("Uploading {}", temp_path);
+      let mut upload = ftp.put_with_stream(&temp_path).await.map_err(|e| ftp_error(action(), e))?;
+      tokio::io::copy(&mut file, &mut upload).await.map_err(|e| ftp_error(action(), FtpError::ConnectionError(e)))?;
+      upload.finish().await.map_err(|e| ftp_error(action(), e))?;
+      Ok(temp_path)
+  }
+
+  /// Moves the fully written `temp_path` over `path`. Servers differ on whether a
+  /// rename may overwrite, so if it's refused the old file is removed and it's retried.
+  async fn replace(&self, ftp: &mut AsyncRustlsFtpStream, temp_path: &str, path: &str) -> Result<()> {
+      if ftp.rename(temp_path, path).await.is_err() {
+          let _ = ftp.rm(path).await;
+          ftp.rename(temp_path, path).await.map_err(|e| ftp_error(format!("Moving {} into place", path), e))?;
+      }
+      self.partials.lock().await.remove(path);
+      Ok(())
+  }
+
+  /// Sets a file's modification time with MFMT, where the server supports it.
+  async fn set_modified(&self, ftp: &mut AsyncRustlsFtpStream, path: &str, modified: SystemTime) {
+      let secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+      let time_of_day = secs % 86400;
+      let timestamp = format!(
+          "{}{:02}{:02}{:02}",
+          format_date(secs).replace('-', ""),
+          time_of_day / 3600,
+          time_of_day / 60 % 60,
+          time_of_day % 60,
+      );
+      let _ = ftp.custom_command(format!("MFMT {} {}", timestamp, path), &[Status::File]).await;
+  }
+
+  /// Fails unless the base path is a folder on the server, for `list` to tell an
+  /// empty folder from one that has gone missing.
+  async fn check_base_path(&self, ftp: &mut AsyncRustlsFtpStream) -> Result<()> {
+      let line = ftp.mlst(Some(&self.base_path)).await
+          .map_err(|e| ftp_error(format!("Checking folder {} on {}", self.base_path, self.host), e))?;
+      match ListParser::parse_mlst(&line) {
+          Ok(file) if !file.is_directory() => Err(IceBucketError::Config(format!("{} on {} isn't a folder", self.base_path, self.host))),
+          _ => Ok(()),
+      }
+  }
+
+  /// Collects every file in `folder` and the folders below it, keyed by its path
+  /// below the base path. A folder that's missing is skipped.
+  async fn list_folder(&self, ftp: &mut AsyncRustlsFtpStream, folder: String, objects: &mut Vec<RemoteObject>) -> Result<()> {
+      let mut pending = vec![folder];
+      while let Some(folder) = pending.pop() {
+          let path = if folder.is_empty() { self.base_path.clone() } else { self.path_for(&folder) };
+          let lines = match ftp.mlsd(Some(&path)).await {
+              Ok(lines) => lines,
+              Err(e) if is_not_found(&e) => continue,
+              Err(e) => return Err(ftp_error(format!("Listing {}", path), e)),
+          };
+          // The current and parent folder entries don't parse and are skipped with the rest
+          for file in lines.iter().filter_map(|line| ListParser::parse_mlsd(line).ok()) {
+              let name = file.name();
+              if name == "." || name == ".." {
+                  continue;
+              }
+              let key = if folder.is_empty() { name.to_string() } else { format!("{}/{}", folder, name) };
+              if file.is_directory() {
+                  pending.push(key);
+              } else if file.is_file() {
+                  objects.push(remote_object(&key, &file));
+              }
+          }
+      }
+      Ok(())
+  }
+}
+
+#[async_trait]
+impl StorageBackend for FtpBackend {
+  fn name(&self) -> &'static str {
+      "FTP"
+  }
+
+  async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+      let folder = folder_of(prefix);
+      let mut objects = Vec::new();
+      let mut ftp = self.ftp.lock().await;
+      self.list_folder(&mut ftp, folder.to_string(), &mut objects).await?;
+      if objects.is_empty() {
+          self.check_base_path(&mut ftp).await?;
+      }
+      objects.retain(|object| object.key.starts_with(prefix));
+      Ok(objects)
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<RemoteObject>> {
+      let mut ftp = self.ftp.lock().await;
+      self.stat_with(&mut ftp, key).await
+  }
+
+  async fn put(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let total_bytes = fs::metadata(file_path).map(|metadata| metadata.len()).unwrap_or(0);
+      let path = self.path_for(key);
+      log.add_entry(LogEntry::new(file_path.to_string(), self.host.clone(), path.clone(), total_bytes));
+
+      let mut ftp = self.ftp.lock().await;
+      self.create_parents(&mut ftp, key).await;
+      let temp_path = self.send_file(&mut ftp, file_path, &path, total_bytes).await?;
+      self.replace(&mut ftp, &temp_path, &path).await?;
+      if let Some(modified) = modified_from(metadata) {
+          self.set_modified(&mut ftp, &path, modified).await;
+      }
+
+      log.update_entry(file_path, total_bytes);
+      let written = self.stat_with(&mut ftp, key).await?
+          .ok_or_else(|| IceBucketError::NotFound(format!("Uploaded file {} is missing", path)))?;
+      Ok(UploadedObject {
+          e_tag: written.e_tag,
+          version_id: None,
+      })
+  }
+
+  async fn get(&self, key: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject> {
+      let mut ftp = self.ftp.lock().await;
+      let remote = self.stat_with(&mut ftp, key).await?
+          .ok_or_else(|| IceBucketError::NotFound(format!("Downloading {}", key)))?;
+      let file_path = destination.to_string_lossy().to_string();
+      log.add_entry(LogEntry::new(file_path.clone(), self.host.clone(), self.path_for(key), remote.size));
+
+      if let Some(parent) = destination.parent() {
+          fs::create_dir_all(parent).map_err(|e| IceBucketError::io(parent.display(), e))?;
+      }
+      download_to(&mut ftp, &self.path_for(key), temp_path).await?;
+      fs::rename(temp_path, destination).map_err(|e| IceBucketError::io(destination.display(), e))?;
+
+      log.update_entry(&file_path, remote.size);
+      Ok(remote)
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+      let mut ftp = self.ftp.lock().await;
+      match ftp.rm(self.path_for(key)).await {
+          Ok(()) => Ok(()),
+          Err(e) if is_not_found(&e) => Ok(()),
+          Err(e) => Err(ftp_error(format!("Deleting {}", key), e)),
+      }
+  }
+
+  /// FTP can't copy on the server, so the content makes a round trip through a local
+  /// temp file. The copy gets the current time as its modification time, which the
+  /// trash relies on.
+  async fn copy(&self, from: &str, to: &str) -> Result<()> {
+      let copy_number = COPY_COUNTER.fetch_add(1, Ordering::Relaxed);
+      let local_temp = env::temp_dir().join(format!("icebucket-ftp-copy-{}-{}{}", std::process::id(), copy_number, TEMP_FILE_SUFFIX));
+      let mut ftp = self.ftp.lock().await;
+      let result = async {
+          download_to(&mut ftp, &self.path_for(from), &local_temp).await?;
+
+          self.create_parents(&mut ftp, to).await;
+          let path = self.path_for(to);
+          let total_bytes = fs::metadata(&local_temp).map(|metadata| metadata.len()).unwrap_or(0);
+          let temp_path = self.send_file(&mut ftp, &local_temp.to_string_lossy(), &path, total_bytes).await?;
+          self.replace(&mut ftp, &temp_path, &path).await
+      }.await;
+      let _ = fs::remove_file(&local_temp);
+      result
+  }
+
+  async fn still_connected(&self) -> bool {
+      // Servers close control connections that sit idle between syncs
+      self.ftp.lock().await.noop().await.is_ok()
+  }
+
+  async fn set_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<()> {
+      if let Some(modified) = modified_from(metadata) {
+          let mut ftp = self.ftp.lock().await;
+          self.set_modified(&mut ftp, &self.path_for(key), modified).await;
+      }
+      Ok(())
+  }
+}
+
+async fn open_connection(sync_settings: &SyncSettings) -> FtpResult<AsyncRustlsFtpStream> {
+  let host = sync_settings.endpoint.as_str();
+  let implicit = sync_settings.tls == "implicit";
+  let port = match sync_settings.port {
+      0 if implicit => 990,
+      0 => 21,
+      port => port,
+  };
+  let address = format!("{}:{}", host, port);
+
+  let mut ftp = match sync_settings.tls.as_str() {
+      "none" => AsyncRustlsFtpStream::connect(&address).await?,
+      "implicit" => AsyncRustlsFtpStream::connect_secure_implicit(&address, tls_connector(), host).await?,
+      _ => AsyncRustlsFtpStream::connect(&address).await?.into_secure(tls_connector(), host).await?,
+  };
+  ftp.login(sync_settings.access_key.as_str(), sync_settings.secret_key.as_str()).await?;
+  ftp.transfer_type(FileType::Binary).await?;
+  // Passive mode gets through client-side NAT and firewalls. Some servers behind NAT
+  // advertise their private address, so connect back to the control address instead.
+  ftp.set_mode(Mode::Passive);
+  ftp.set_passive_nat_workaround(true);
+  Ok(ftp)
+}
+
+/// Verifies servers against the certificates trusted by the operating system.
+fn tls_connector() -> AsyncRustlsConnector {
+  let mut roots = RootCertStore::empty();
+  let native = rustls_native_certs::load_native_certs();
+  for e in &native.errors {
+      eprintln!("Problem loading system certificates: {}", e);
+  }
+  roots.add_parsable_certificates(native.certs);
+  let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+      .with_safe_default_protocol_versions()
+      .expect("Default TLS versions are supported")
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+  AsyncRustlsConnector::from(TlsConnector::from(Arc::new(config)))
+}
+
+async fn download_to(ftp: &mut AsyncRustlsFtpStream, path: &str, destination: &Path) -> Result<()> {
+  let action = || format!("Downloading {}", path);
+  let mut download = ftp.retr_as_stream(path).await.map_err(|e| ftp_error(action(), e))?;
+  let mut file = tokio::fs::File::create(destination).await.map_err(|e| IceBucketError::io(destination.display(), e))?;
+  tokio::io::copy(&mut download, &mut file).await.map_err(|e| ftp_error(action(), FtpError::ConnectionError(e)))?;
+  download.finish().await.map_err(|e| ftp_error(action(), e))?;
+  file.sync_all().await.map_err(|e| IceBucketError::io(destination.display(), e))
+}
+
+/// Where an upload to `path` of a file with this size and modification time is
+/// written before it's moved into place.
+fn temp_path_for(path: &str, size: u64, modified: Option<SystemTime>) -> String {
+  let modified = modified.map(system_time_to_nanos).unwrap_or(0);
+  format!("{}.{}-{}{}", path, size, modified, TEMP_FILE_SUFFIX)
+}
+
+fn remote_object(key: &str, file: &ListedFile) -> RemoteObject {
+  let modified = file.modified();
+  let secs = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  RemoteObject {
+      key: key.to_string(),
+      size: file.size() as u64,
+      e_tag: Some(format!("{}-{}", file.size(), secs)),
+      version_id: None,
+      last_modified: Some(modified),
+      metadata: HashMap::new(),
+  }
+}
+
+/// Classifies a failed command by the server's reply code.
+fn ftp_error(action: String, error: FtpError) -> IceBucketError {
+  let message = format!("{}: {}", action, error);
+  match &error {
+      FtpError::UnexpectedResponse(response) => match response.status {
+          Status::NotLoggedIn => IceBucketError::Auth(message),
+          Status::FileUnavailable => IceBucketError::NotFound(message),
+          Status::NotAvailable | Status::CannotOpenDataConnection | Status::TransferAborted | Status::HostUnavailable => IceBucketError::Network(message),
+          // The server's way of saying to try again later
+          Status::RequestFileActionIgnored | Status::ActionAborted | Status::RequestedActionNotTaken => IceBucketError::Server(message),
+          _ => IceBucketError::Service(message),
+      },
+      FtpError::InvalidAddress(_) => IceBucketError::Config(message),
+      FtpError::BadResponse => IceBucketError::Service(message),
+      _ => IceBucketError::Network(message),
+  }
+}
+
+fn is_not_found(error: &FtpError) -> bool {
+  matches!(error, FtpError::UnexpectedResponse(response) if response.status == Status::FileUnavailable)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  #[test]
+  fn temp_path_for_tells_versions_apart() {
+      let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+      let temp_path = temp_path_for("base/a.txt", 5, Some(modified));
+      assert!(temp_path.starts_with("base/a.txt."));
+      assert!(temp_path.ends_with(TEMP_FILE_SUFFIX));
+      assert_eq!(temp_path, temp_path_for("base/a.txt", 5, Some(modified)));
+      assert_ne!(temp_path, temp_path_for("base/a.txt", 6, Some(modified)));
+      assert_ne!(temp_path, temp_path_for("base/a.txt", 5, Some(modified + Duration::from_secs(1))));
+  }
+}
@@ -0,0 +1,510 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL};
+use md5::{Digest, Md5};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use ring::rand::SystemRandom;
+use ring::signature::{RSA_PKCS1_SHA256, RsaKeyPair};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+use crate::error::{IceBucketError, Result};
+use crate::logger::{LogEntry, Log};
+use crate::SyncSettings;
+use super::http::{check, header, response_error};
+use super::{RemoteObject, StorageBackend, UploadedObject};
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Size of each request in a resumable upload. Must be a multiple of 256KB.
+const CHUNK_SIZE: usize = 8 * 1024 * 1024; // 8MB
+
+/// How many times in a row a resumable upload may be interrupted before giving up.
+const MAX_UPLOAD_INTERRUPTIONS: u32 = 5;
+
+/// Syncs to a Google Cloud Storage bucket, authenticating as the service account
+/// whose JSON key file is `credentials_file`.
+///
+/// Objects are told apart by their generation number, which changes whenever the
+/// object is overwritten. Uploads send their CRC32C and MD5 so the server rejects a
+/// corrupted transfer, and downloads are checked against the object's CRC32C.
+pub struct GcsBackend {
+  http: Client,
+  endpoint: String,
+  bucket: String,
+  /// `None` when talking to an emulator that doesn't check credentials.
+  service_account: Option<ServiceAccount>,
+  /// The current access token and when it expires.
+  token: Mutex<Option<(String, Instant)>>,
+}
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+  client_email: String,
+  private_key: String,
+  token_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+  access_token: String,
+  expires_in: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectResource {
+  name: String,
+  size: String,
+  generation: String,
+  crc32c: Option<String>,
+  md5_hash: Option<String>,
+  updated: Option<String>,
+  #[serde(default)]
+  metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectList {
+  #[serde(default)]
+  items: Vec<ObjectResource>,
+  next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RewriteResponse {
+  done: bool,
+  rewrite_token: Option<String>,
+}
+
+impl GcsBackend {
+  /// Reads the service account key. `credentials_file` falls back to
+  /// `GOOGLE_APPLICATION_CREDENTIALS`; with neither, only a custom `endpoint` (an
+  /// emulator such as fake-gcs-server) can be used. Fails if the key can't be read.
+  pub fn new(sync_settings: &SyncSettings) -> Result<Self> {
+      let credentials_file = if sync_settings.credentials_file.is_empty() {
+          env::var("GOOGLE_APPLICATION_CREDENTIALS").unwrap_or_default()
+      } else {
+          sync_settings.credentials_file.clone()
+      };
+
+      let service_account = if credentials_file.is_empty() {
+          if sync_settings.endpoint.is_empty() {
+              return Err(IceBucketError::Config(format!("no credentials_file set for Google Cloud Storage bucket {}", sync_settings.bucket)));
+          }
+          None
+      } else {
+          let key = fs::read_to_string(&credentials_file)
+              .map_err(|e| IceBucketError::Config(format!("reading {}: {}", credentials_file, e)))?;
+          let service_account = serde_json::from_str(&key)
+              .map_err(|e| IceBucketError::Config(format!("{} isn't a service account key: {}", credentials_file, e)))?;
+          Some(service_account)
+      };
+
+      let endpoint = if sync_settings.endpoint.is_empty() { DEFAULT_ENDPOINT } else { sync_settings.endpoint.as_str() };
+      Ok(GcsBackend {
+          http: Client::new(),
+          endpoint: endpoint.trim_end_matches('/').to_string(),
+          bucket: sync_settings.bucket.clone(),
+          service_account,
+          token: Mutex::new(None),
+      })
+  }
+
+  /// Adds an access token to a request, fetching a new one when the current one is
+  /// about to expire.
+  async fn authorize(&self, request: RequestBuilder) -> Result<RequestBuilder> {
+      let Some(service_account) = &self.service_account else { return Ok(request) };
+      let mut token = self.token.lock().await;
+      let still_valid = token.as_ref().is_some_and(|(_, expires)| *expires > Instant::now() + Duration::from_secs(60));
+      if !still_valid {
+          *token = Some(fetch_token(&self.http, service_account).await?);
+      }
+      Ok(request.bearer_auth(&token.as_ref().expect("Token was just fetched").0))
+  }
+
+  async fn send(&self, request: RequestBuilder) -> Result<Response> {
+      Ok(self.authorize(request).await?.send().await?)
+  }
+
+  fn object_url(&self, key: &str) -> String {
+      format!("{}/storage/v1/b/{}/o/{}", self.endpoint, encode(&self.bucket), encode(key))
+  }
+
+  fn upload_url(&self, upload_type: &str) -> String {
+      format!("{}/upload/storage/v1/b/{}/o?uploadType={}", self.endpoint, encode(&self.bucket), upload_type)
+  }
+
+  /// Asks how much of a resumable upload the server has, after a chunk failed.
+  /// Returns `None` if the upload turns out to be complete.
+  async fn upload_status(&self, session: &str, total_bytes: u64) -> Result<Option<u64>> {
+      let request = self.http.put(session).header("Content-Range", format!("bytes */{}", total_bytes));
+      let response = self.send(request).await?;
+      if response.status() == StatusCode::PERMANENT_REDIRECT {
+          return Ok(Some(committed_bytes(header(&response, "range").as_deref())));
+      }
+      check(response, "Checking upload progress").await?;
+      Ok(None)
+  }
+}
+
+#[async_trait]
+impl StorageBackend for GcsBackend {
+  fn name(&self) -> &'static str {
+      "GCS"
+  }
+
+  /// Lists every object under `prefix`, following page tokens past the 1000-object
+  /// page limit.
+  async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+      let url = format!("{}/storage/v1/b/{}/o", self.endpoint, encode(&self.bucket));
+      let mut objects = Vec::new();
+      let mut page_token = None;
+      loop {
+          let mut request = self.http.get(&url).query(&[("prefix", prefix)]);
+          if let Some(page_token) = &page_token {
+              request = request.query(&[("pageToken", page_token)]);
+          }
+          let response = check(self.send(request).await?, "Listing objects").await?;
+          let page: ObjectList = response.json().await?;
+          objects.extend(page.items.into_iter().map(remote_object));
+          page_token = page.next_page_token;
+          if page_token.is_none() {
+              break;
+          }
+      }
+      Ok(objects)
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<RemoteObject>> {
+      let response = self.send(self.http.get(self.object_url(key))).await?;
+      if response.status() == StatusCode::NOT_FOUND {
+          return Ok(None);
+      }
+      let response = check(response, &format!("Checking object {}", key)).await?;
+      Ok(Some(remote_object(response.json().await?)))
+  }
+
+  async fn put(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let content = fs::read(file_path).map_err(|e| IceBucketError::io(file_path, e))?;
+      let total_bytes = content.len() as u64;
+      log.add_entry(LogEntry::new(file_path.to_string(), self.bucket.clone(), key.to_string(), total_bytes));
+
+      let crc32c = encode_crc32c(crc32c::crc32c(&content));
+      let md5 = BASE64.encode(Md5::digest(&content));
+      let resource = json!({
+          "name": key,
+          "metadata": metadata,
+          "crc32c": crc32c,
+          "md5Hash": md5,
+      });
+
+      // A multipart/related body carries the object's resource and its content together
+      let boundary = format!("icebucket-{}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+      let mut body = format!(
+          "--{boundary}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{resource}\r\n--{boundary}\r\nContent-Type: application/octet-stream\r\n\r\n"
+      ).into_bytes();
+      body.extend_from_slice(&content);
+      body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+      let request = self.http.post(self.upload_url("multipart"))
+          .header("Content-Type", format!("multipart/related; boundary={}", boundary))
+          .body(body);
+      let response = check(self.send(request).await?, &format!("Uploading {}", key)).await?;
+      let object: ObjectResource = response.json().await?;
+      check_upload(key, &object, &crc32c, &md5)?;
+
+      log.update_entry(file_path, total_bytes);
+      Ok(UploadedObject {
+          e_tag: Some(object.generation.clone()),
+          version_id: Some(object.generation),
+      })
+  }
+
+  /// Sends the file in chunks over a resumable upload session. When a chunk fails,
+  /// the server is asked how much it has and the upload carries on from there.
+  async fn put_multipart(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let file_error = |e| IceBucketError::io(file_path, e);
+      let mut file = fs::File::open(file_path).map_err(file_error)?;
+      let total_bytes = file.metadata().map_err(file_error)?.len();
+      log.add_entry(LogEntry::new(file_path.to_string(), self.bucket.clone(), key.to_string(), total_bytes));
+
+      let (crc32c, md5) = checksums(&mut file).map_err(file_error)?;
+      let resource = json!({
+          "name": key,
+          "metadata": metadata,
+          "crc32c": crc32c,
+          "md5Hash": md5,
+      });
+      let request = self.http.post(self.upload_url("resumable"))
+          .header("X-Upload-Content-Length", total_bytes)
+          .json(&resource);
+      let response = check(self.send(request).await?, &format!("Starting upload of {}", key)).await?;
+      let session = header(&response, "location")
+          .ok_or_else(|| IceBucketError::Service(format!("Starting upload of {}: the session has no location", key)))?;
+
+      let mut offset = 0;
+      let mut interruptions = 0;
+      let object = loop {
+          let chunk_len = (CHUNK_SIZE as u64).min(total_bytes.saturating_sub(offset));
+          let mut buffer = Vec::with_capacity(chunk_len as usize);
+          file.seek(SeekFrom::Start(offset)).map_err(file_error)?;
+          (&mut file).take(chunk_len).read_to_end(&mut buffer).map_err(file_error)?;
+          if buffer.is_empty() || (buffer.len() as u64) < chunk_len {
+              // The session was started for the size the file had then
+              return Err(file_error(io::Error::new(io::ErrorKind::UnexpectedEof, "the file shrank while it was being uploaded")));
+          }
+          let end = offset + buffer.len() as u64;
+
+          let request = self.http.put(&session)
+              .header("Content-Range", format!("bytes {}-{}/{}", offset, end - 1, total_bytes))
+              .body(buffer);
+          let failure = match self.authorize(request).await?.send().await {
+              Ok(response) if response.status() == StatusCode::PERMANENT_REDIRECT => {
+                  let committed = committed_bytes(header(&response, "range").as_deref());
+                  log.add_progress(file_path, committed.saturating_sub(offset));
+                  offset = committed;
+                  interruptions = 0;
+                  continue;
+              }
+              Ok(response) if response.status().is_success() => {
+                  break response.json::<ObjectResource>().await?;
+              }
+              Ok(response) if response.status().is_server_error() => IceBucketError::from_status(response.status().as_u16(), response.status().to_string()),
+              Ok(response) => return Err(response_error(response, &format!("Uploading {}", key)).await),
+              Err(e) => IceBucketError::from(e),
+          };
+
+          interruptions += 1;
+          if interruptions > MAX_UPLOAD_INTERRUPTIONS {
+              return Err(failure);
+          }
+          eprintln!("Upload of {} interrupted at {} of {} bytes, resuming: {}", key, offset, total_bytes, failure);
+          match self.upload_status(&session, total_bytes).await? {
+              Some(committed) => offset = committed,
+              None => {
+                  // The last chunk landed even though its response didn't
+                  break fetch_object(self, key).await?;
+              }
+          }
+      };
+      check_upload(key, &object, &crc32c, &md5)?;
+
+      log.update_entry(file_path, total_bytes);
+      Ok(UploadedObject {
+          e_tag: Some(object.generation.clone()),
+          version_id: Some(object.generation),
+      })
+  }
+
+  async fn get(&self, key: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject> {
+      let object = fetch_object(self, key).await?;
+      let expected_crc32c = object.crc32c.clone();
+      let remote = remote_object(object);
+      let file_path = destination.to_string_lossy().to_string();
+      log.add_entry(LogEntry::new(file_path.clone(), self.bucket.clone(), key.to_string(), remote.size));
+
+      if let Some(parent) = destination.parent() {
+          fs::create_dir_all(parent).map_err(|e| IceBucketError::io(parent.display(), e))?;
+      }
+      // Asking for that generation means an overwrite mid-download can't mix versions
+      let request = self.http.get(self.object_url(key))
+          .query(&[("alt", "media"), ("generation", remote.e_tag.as_deref().unwrap_or_default())]);
+      let mut response = check(self.send(request).await?, &format!("Downloading {}", key)).await?;
+      let temp_error = |e| IceBucketError::io(temp_path.display(), e);
+      let mut file = fs::File::create(temp_path).map_err(temp_error)?;
+      let mut crc32c = 0;
+      while let Some(chunk) = response.chunk().await? {
+          crc32c = crc32c::crc32c_append(crc32c, &chunk);
+          file.write_all(&chunk).map_err(temp_error)?;
+          log.add_progress(&file_path, chunk.len() as u64);
+      }
+      file.sync_all().map_err(temp_error)?;
+      drop(file);
+
+      if let Some(expected) = expected_crc32c {
+          if encode_crc32c(crc32c) != expected {
+              let _ = fs::remove_file(temp_path);
+              return Err(IceBucketError::Service(format!("Download of {} is corrupt: CRC32C doesn't match", key)));
+          }
+      }
+      fs::rename(temp_path, destination).map_err(|e| IceBucketError::io(destination.display(), e))?;
+
+      log.update_entry(&file_path, remote.size);
+      Ok(remote)
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+      let response = self.send(self.http.delete(self.object_url(key))).await?;
+      if response.status() != StatusCode::NOT_FOUND {
+          check(response, &format!("Deleting {}", key)).await?;
+      }
+      Ok(())
+  }
+
+  /// Rewrites the object within the bucket. Large objects can take several calls,
+  /// each continuing from the token the last one returned.
+  async fn copy(&self, from: &str, to: &str) -> Result<()> {
+      let url = format!("{}/rewriteTo/b/{}/o/{}", self.object_url(from), encode(&self.bucket), encode(to));
+      let mut rewrite_token = None;
+      loop {
+          let mut request = self.http.post(&url).header("Content-Length", 0);
+          if let Some(rewrite_token) = &rewrite_token {
+              request = request.query(&[("rewriteToken", rewrite_token)]);
+          }
+          let response = check(self.send(request).await?, &format!("Copying {} to {}", from, to)).await?;
+          let rewrite: RewriteResponse = response.json().await?;
+          if rewrite.done {
+              return Ok(());
+          }
+          rewrite_token = rewrite.rewrite_token;
+      }
+  }
+
+  async fn set_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<()> {
+      // A patch merges metadata, so keys that are going away have to be cleared
+      let mut patch: serde_json::Map<String, serde_json::Value> = metadata.iter()
+          .map(|(name, value)| (name.clone(), json!(value)))
+          .collect();
+      if let Some(current) = self.stat(key).await? {
+          for name in current.metadata.keys().filter(|name| !metadata.contains_key(*name)) {
+              patch.insert(name.clone(), serde_json::Value::Null);
+          }
+      }
+      let request = self.http.patch(self.object_url(key)).json(&json!({ "metadata": patch }));
+      check(self.send(request).await?, &format!("Setting metadata on {}", key)).await?;
+      Ok(())
+  }
+
+  async fn versioning_enabled(&self) -> Result<bool> {
+      let url = format!("{}/storage/v1/b/{}", self.endpoint, encode(&self.bucket));
+      let response = check(self.send(self.http.get(url).query(&[("fields", "versioning")])).await?, "Checking bucket versioning").await?;
+      let bucket: serde_json::Value = response.json().await?;
+      Ok(bucket["versioning"]["enabled"].as_bool().unwrap_or(false))
+  }
+}
+
+/// Exchanges a JWT signed with the service account's key for an access token.
+async fn fetch_token(http: &Client, service_account: &ServiceAccount) -> Result<(String, Instant)> {
+  let issued_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+  let header = BASE64_URL.encode(json!({ "alg": "RS256", "typ": "JWT" }).to_string());
+  let claims = BASE64_URL.encode(json!({
+      "iss": service_account.client_email,
+      "scope": SCOPE,
+      "aud": service_account.token_uri,
+      "iat": issued_at,
+      "exp": issued_at + 3600,
+  }).to_string());
+  let unsigned = format!("{}.{}", header, claims);
+
+  // The key is PKCS#8 in PEM armor
+  let der: String = service_account.private_key.lines().filter(|line| !line.starts_with("-----")).collect();
+  let der = BASE64.decode(der)
+      .map_err(|e| IceBucketError::Config(format!("service account private key isn't valid PEM: {}", e)))?;
+  let key_pair = RsaKeyPair::from_pkcs8(&der)
+      .map_err(|e| IceBucketError::Config(format!("service account private key isn't a valid RSA key: {}", e)))?;
+  let mut signature = vec![0; key_pair.public().modulus_len()];
+  key_pair.sign(&RSA_PKCS1_SHA256, &SystemRandom::new(), unsigned.as_bytes(), &mut signature)
+      .map_err(|e| IceBucketError::Config(format!("signing token request: {}", e)))?;
+  let assertion = format!("{}.{}", unsigned, BASE64_URL.encode(signature));
+
+  let response = http.post(&service_account.token_uri)
+      .form(&[("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", assertion.as_str())])
+      .send()
+      .await?;
+  // The token endpoint turns down bad keys with a 400 rather than a 401
+  if response.status().is_client_error() {
+      let status = response.status();
+      let details = response.text().await.unwrap_or_default();
+      return Err(IceBucketError::Auth(format!("Signing in to Google Cloud failed with {}: {}", status, details)));
+  }
+  let token: TokenResponse = check(response, "Signing in to Google Cloud").await?.json().await?;
+  Ok((token.access_token, Instant::now() + Duration::from_secs(token.expires_in)))
+}
+
+async fn fetch_object(backend: &GcsBackend, key: &str) -> Result<ObjectResource> {
+  let response = backend.send(backend.http.get(backend.object_url(key))).await?;
+  Ok(check(response, &format!("Checking object {}", key)).await?.json().await?)
+}
+
+/// Reads the file once to work out its CRC32C and MD5, both base64 encoded as the
+/// JSON API expects them.
+fn checksums(file: &mut fs::File) -> std::io::Result<(String, String)> {
+  let mut crc32c = 0;
+  let mut md5 = Md5::new();
+  let mut buffer = vec![0; CHUNK_SIZE];
+  loop {
+      let bytes_read = file.read(&mut buffer)?;
+      if bytes_read == 0 {
+          break;
+      }
+      crc32c = crc32c::crc32c_append(crc32c, &buffer[..bytes_read]);
+      md5.update(&buffer[..bytes_read]);
+  }
+  Ok((encode_crc32c(crc32c), BASE64.encode(md5.finalize())))
+}
+
+fn encode_crc32c(crc32c: u32) -> String {
+  BASE64.encode(crc32c.to_be_bytes())
+}
+
+/// Makes sure what the server stored matches the file, for servers (emulators, mostly)
+/// that don't check the checksums sent with the upload themselves.
+fn check_upload(key: &str, object: &ObjectResource, crc32c: &str, md5: &str) -> Result<()> {
+  let crc32c_matches = object.crc32c.as_deref().is_none_or(|stored| stored == crc32c);
+  let md5_matches = object.md5_hash.as_deref().is_none_or(|stored| stored == md5);
+  if !crc32c_matches || !md5_matches {
+      return Err(IceBucketError::Service(format!("Upload of {} is corrupt: checksums don't match", key)));
+  }
+  Ok(())
+}
+
+/// How many bytes a resumable upload has stored, from the `Range: bytes=0-N` header
+/// of a 308 response. No header means nothing has been stored yet.
+fn committed_bytes(range: Option<&str>) -> u64 {
+  range
+      .and_then(|range| range.rsplit('-').next().and_then(|end| end.parse::<u64>().ok()))
+      .map(|end| end + 1)
+      .unwrap_or(0)
+}
+
+fn encode(value: &str) -> String {
+  utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+fn remote_object(object: ObjectResource) -> RemoteObject {
+  RemoteObject {
+      key: object.name,
+      size: object.size.parse().unwrap_or(0),
+      e_tag: Some(object.generation.clone()),
+      version_id: Some(object.generation),
+      last_modified: object.updated
+          .and_then(|updated| chrono::DateTime::parse_from_rfc3339(&updated).ok())
+          .map(SystemTime::from),
+      metadata: object.metadata,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn committed_bytes_reads_the_range_end() {
+      assert_eq!(committed_bytes(Some("bytes=0-99")), 100);
+      assert_eq!(committed_bytes(Some("bytes=0-0")), 1);
+      assert_eq!(committed_bytes(Some("bytes=0-")), 0);
+      assert_eq!(committed_bytes(Some("garbage")), 0);
+      assert_eq!(committed_bytes(None), 0);
+  }
+}
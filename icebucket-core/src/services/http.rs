@@ -0,0 +1,29 @@
+use reqwest::Response;
+use crate::error::{IceBucketError, Result};
+
+// Helpers shared by the backends that talk HTTP (Azure, GCS and WebDAV).
+
+/// Passes a successful response through, or turns a failed one into an error with
+/// the server's details.
+pub async fn check(response: Response, action: &str) -> Result<Response> {
+  if response.status().is_success() {
+      return Ok(response);
+  }
+  Err(response_error(response, action).await)
+}
+
+/// The error for a response that failed, classified by its status.
+pub async fn response_error(response: Response, action: &str) -> IceBucketError {
+  let status = response.status();
+  let details = response.text().await.unwrap_or_default();
+  IceBucketError::from_status(status.as_u16(), format!("{} failed with {}: {}", action, status, details))
+}
+
+pub fn header(response: &Response, name: &str) -> Option<String> {
+  response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string)
+}
+
+/// The text of `node`'s first child element called `name`, in an XML response.
+pub fn child_text<'a>(node: roxmltree::Node<'a, '_>, name: &str) -> Option<&'a str> {
+  node.children().find(|child| child.has_tag_name(name)).and_then(|child| child.text())
+}
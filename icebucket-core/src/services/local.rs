@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use crate::error::{IceBucketError, Result};
+use crate::logger::{LogEntry, Log};
+use crate::state::system_time_to_nanos;
+use crate::sync::{MTIME_METADATA_KEY, TEMP_FILE_SUFFIX};
+use crate::SyncSettings;
+use super::{RemoteObject, StorageBackend, UploadedObject, folder_of, modified_from};
+
+/// Mirrors folders into another folder, such as an external drive, a NAS mount or an
+/// SMB share. `bucket` is the destination folder and object keys are paths below it.
+///
+/// Plain files have no user metadata, so the modification time stands in for it: it
+/// is set from the `mtime` metadata on upload and reported back as that metadata, and
+/// together with the size it serves as the ETag.
+pub struct LocalBackend {
+  root: PathBuf,
+}
+
+impl LocalBackend {
+  pub fn new(sync_settings: &SyncSettings) -> Self {
+      LocalBackend {
+          root: PathBuf::from(&sync_settings.bucket),
+      }
+  }
+
+  fn path_for(&self, key: &str) -> PathBuf {
+      key.split('/').fold(self.root.clone(), |path, part| path.join(part))
+  }
+}
+
+#[async_trait]
+impl StorageBackend for LocalBackend {
+  fn name(&self) -> &'static str {
+      "Local"
+  }
+
+  async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+      let folder = folder_of(prefix);
+      let start = if folder.is_empty() { self.root.clone() } else { self.path_for(folder) };
+      let mut objects = Vec::new();
+      list_files(&self.root, &start, &mut objects)?;
+      // Missing folders list as empty, but an unplugged drive or unmounted share must
+      // not, or a two-way sync would take every file as deleted at the destination
+      if objects.is_empty() && !self.root.is_dir() {
+          return Err(IceBucketError::NotFound(format!("Destination folder {}", self.root.display())));
+      }
+      objects.retain(|object| object.key.starts_with(prefix));
+      Ok(objects)
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<RemoteObject>> {
+      let path = self.path_for(key);
+      match fs::metadata(&path) {
+          Ok(metadata) => Ok(metadata.is_file().then(|| remote_object(key, &metadata))),
+          Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+          Err(e) => Err(IceBucketError::io(path.display(), e)),
+      }
+  }
+
+  async fn put(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let destination = self.path_for(key);
+      let total_bytes = fs::metadata(file_path).map(|metadata| metadata.len()).unwrap_or(0);
+      log.add_entry(LogEntry::new(file_path.to_string(), self.root.to_string_lossy().to_string(), key.to_string(), total_bytes));
+
+      copy_into_place(Path::new(file_path), &destination, modified_from(metadata))?;
+
+      log.update_entry(file_path, total_bytes);
+      let written = fs::metadata(&destination).map_err(|e| IceBucketError::io(destination.display(), e))?;
+      Ok(UploadedObject {
+          e_tag: Some(e_tag_for(&written)),
+          version_id: None,
+      })
+  }
+
+  async fn get(&self, key: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject> {
+      let source = self.path_for(key);
+      let metadata = fs::metadata(&source).map_err(|e| match e.kind() {
+          std::io::ErrorKind::NotFound => IceBucketError::NotFound(format!("Downloading {}", key)),
+          _ => IceBucketError::io(source.display(), e),
+      })?;
+      let file_path = destination.to_string_lossy().to_string();
+      log.add_entry(LogEntry::new(file_path.clone(), self.root.to_string_lossy().to_string(), key.to_string(), metadata.len()));
+
+      if let Some(parent) = destination.parent() {
+          fs::create_dir_all(parent).map_err(|e| IceBucketError::io(parent.display(), e))?;
+      }
+      fs::copy(&source, temp_path).map_err(|e| IceBucketError::io(source.display(), e))?;
+      fs::rename(temp_path, destination).map_err(|e| IceBucketError::io(destination.display(), e))?;
+
+      log.update_entry(&file_path, metadata.len());
+      Ok(remote_object(key, &metadata))
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+      let path = self.path_for(key);
+      match fs::remove_file(&path) {
+          Ok(()) => remove_empty_parents(&self.root, &path),
+          Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+          Err(e) => return Err(IceBucketError::io(path.display(), e)),
+      }
+      Ok(())
+  }
+
+  /// The copy gets the current time as its modification time, as an S3 copy would;
+  /// the trash relies on that to know when a file was trashed.
+  async fn copy(&self, from: &str, to: &str) -> Result<()> {
+      let source = self.path_for(from);
+      if !source.is_file() {
+          return Err(IceBucketError::NotFound(format!("Copying {}", from)));
+      }
+      copy_into_place(&source, &self.path_for(to), Some(SystemTime::now()))
+  }
+
+  async fn set_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<()> {
+      if let Some(modified) = modified_from(metadata) {
+          let path = self.path_for(key);
+          fs::OpenOptions::new()
+              .write(true)
+              .open(&path)
+              .and_then(|file| file.set_modified(modified))
+              .map_err(|e| IceBucketError::io(path.display(), e))?;
+      }
+      Ok(())
+  }
+}
+
+/// Copies `source` over `destination` through a temp file next to it, so a reader of
+/// the destination never sees a partial copy, optionally setting its modification time.
+fn copy_into_place(source: &Path, destination: &Path, modified: Option<SystemTime>) -> Result<()> {
+  if let Some(parent) = destination.parent() {
+      fs::create_dir_all(parent).map_err(|e| IceBucketError::io(parent.display(), e))?;
+  }
+  let mut temp_path = destination.as_os_str().to_owned();
+  temp_path.push(TEMP_FILE_SUFFIX);
+  let temp_path = PathBuf::from(temp_path);
+
+  fs::copy(source, &temp_path).map_err(|e| IceBucketError::io(source.display(), e))?;
+  if let Some(modified) = modified {
+      fs::OpenOptions::new()
+          .write(true)
+          .open(&temp_path)
+          .and_then(|file| file.set_modified(modified))
+          .map_err(|e| IceBucketError::io(temp_path.display(), e))?;
+  }
+  fs::rename(&temp_path, destination).map_err(|e| IceBucketError::io(destination.display(), e))
+}
+
+/// Walks `dir` and collects every file as an object keyed by its path below `root`.
+/// A missing `dir` has no files; callers check `root` itself is there.
+fn list_files(root: &Path, dir: &Path, objects: &mut Vec<RemoteObject>) -> Result<()> {
+  let entries = match fs::read_dir(dir) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(IceBucketError::io(dir.display(), e)),
+  };
+  for entry in entries.flatten() {
+      let path = entry.path();
+      let Ok(metadata) = entry.metadata() else { continue };
+      if metadata.is_dir() {
+          list_files(root, &path, objects)?;
+      } else if metadata.is_file() {
+          let key = crate::sync::relative_key(root, &path);
+          objects.push(remote_object(&key, &metadata));
+      }
+  }
+  Ok(())
+}
+
+fn remote_object(key: &str, metadata: &fs::Metadata) -> RemoteObject {
+  let modified = metadata.modified().ok();
+  let mut object_metadata = HashMap::new();
+  if let Some(modified) = modified {
+      object_metadata.insert(MTIME_METADATA_KEY.to_string(), system_time_to_nanos(modified).to_string());
+  }
+  RemoteObject {
+      key: key.to_string(),
+      size: metadata.len(),
+      e_tag: Some(e_tag_for(metadata)),
+      version_id: None,
+      last_modified: modified,
+      metadata: object_metadata,
+  }
+}
+
+fn e_tag_for(metadata: &fs::Metadata) -> String {
+  let modified = metadata.modified().map(system_time_to_nanos).unwrap_or(0);
+  format!("{}-{}", metadata.len(), modified)
+}
+
+/// Removes folders left empty by a deletion, up to but not including `root`.
+fn remove_empty_parents(root: &Path, path: &Path) {
+  let mut dir = path.parent();
+  while let Some(current) = dir {
+      if current == root || !current.starts_with(root) || fs::remove_dir(current).is_err() {
+          break;
+      }
+      dir = current.parent();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::{Duration, UNIX_EPOCH};
+  use crate::testing::temp_dir;
+
+  fn backend_and_source(name: &str) -> (LocalBackend, PathBuf) {
+      let dir = temp_dir(name);
+      let root = dir.join("destination");
+      fs::create_dir(&root).unwrap();
+      let source = dir.join("source.txt");
+      fs::write(&source, "hello").unwrap();
+      (LocalBackend { root }, source)
+  }
+
+  #[tokio::test]
+  async fn put_get_and_delete_round_trip() {
+      let (backend, source) = backend_and_source("local-round-trip");
+      let log = Log::new();
+      let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+      let metadata = HashMap::from([(MTIME_METADATA_KEY.to_string(), system_time_to_nanos(modified).to_string())]);
+
+      let uploaded = backend.put("photos/a.txt", &source.to_string_lossy(), &metadata, &log).await.unwrap();
+      let listed = backend.list("photos/").await.unwrap();
+      assert_eq!(listed.len(), 1);
+      assert_eq!(listed[0].key, "photos/a.txt");
+      assert_eq!(listed[0].size, 5);
+      assert_eq!(listed[0].e_tag, uploaded.e_tag);
+      assert_eq!(modified_from(&listed[0].metadata), Some(modified));
+
+      let destination = source.with_file_name("downloaded.txt");
+      let temp_path = source.with_file_name("downloaded.txt.tmp");
+      let fetched = backend.get("photos/a.txt", &destination, &temp_path, &log).await.unwrap();
+      assert_eq!(fs::read_to_string(&destination).unwrap(), "hello");
+      assert_eq!(fetched.e_tag, uploaded.e_tag);
+      assert!(!temp_path.exists());
+
+      backend.delete("photos/a.txt").await.unwrap();
+      assert!(backend.stat("photos/a.txt").await.unwrap().is_none());
+      assert!(!backend.root.join("photos").exists());
+      assert!(backend.root.is_dir());
+      // Deleting what is already gone is not an error
+      backend.delete("photos/a.txt").await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn copy_to_trash_keeps_the_original() {
+      let (backend, source) = backend_and_source("local-trash");
+      let log = Log::new();
+      backend.put("a.txt", &source.to_string_lossy(), &HashMap::new(), &log).await.unwrap();
+
+      backend.copy("a.txt", ".trash/a.txt").await.unwrap();
+      assert_eq!(fs::read_to_string(backend.root.join(".trash").join("a.txt")).unwrap(), "hello");
+      assert!(backend.stat("a.txt").await.unwrap().is_some());
+
+      let missing = backend.copy("b.txt", ".trash/b.txt").await;
+      assert!(matches!(missing, Err(IceBucketError::NotFound(_))));
+  }
+
+  #[tokio::test]
+  async fn missing_destination_fails_the_listing() {
+      let (backend, _) = backend_and_source("local-missing");
+      assert!(backend.list("").await.unwrap().is_empty());
+      assert!(backend.list("no/such/folder/").await.unwrap().is_empty());
+
+      fs::remove_dir(&backend.root).unwrap();
+      assert!(matches!(backend.list("").await, Err(IceBucketError::NotFound(_))));
+  }
+}
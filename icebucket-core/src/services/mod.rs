@@ -1 +1,117 @@
-pub mod s3;
\ No newline at end of file
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::Log;
+use crate::SyncSettings;
+use crate::error::{IceBucketError, Result};
+use crate::sync::MTIME_METADATA_KEY;
+
+pub mod azure;
+pub mod ftp;
+pub mod gcs;
+pub mod http;
+pub mod local;
+pub mod retry;
+pub mod s3;
+pub mod sftp;
+pub mod webdav;
+
+/// An object as stored by a backend. Listings don't always include user metadata, so
+/// `metadata` is only guaranteed to be filled in by `stat` and `get`.
+#[derive(Clone)]
+pub struct RemoteObject {
+  pub key: String,
+  pub size: u64,
+  pub e_tag: Option<String>,
+  pub version_id: Option<String>,
+  pub last_modified: Option<SystemTime>,
+  pub metadata: HashMap<String, String>,
+}
+
+/// What the backend reported back about an object we just wrote.
+pub struct UploadedObject {
+  pub e_tag: Option<String>,
+  pub version_id: Option<String>,
+}
+
+/// A place folders can be synced to. Keys are `/`-separated and already include the
+/// configured prefix; `e_tag` is whatever opaque version tag the backend has, used to
+/// tell whether an object changed since it was last synced.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+  /// Short name used in progress output, e.g. `"S3"`.
+  fn name(&self) -> &'static str;
+
+  /// Lists every object under `prefix`.
+  async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>>;
+
+  /// Fetches an object's size, version tag and user metadata without downloading it.
+  /// Returns `None` if the object doesn't exist.
+  async fn stat(&self, key: &str) -> Result<Option<RemoteObject>>;
+
+  /// Uploads a file in one request.
+  async fn put(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject>;
+
+  /// Uploads a large file in parts. Backends without a multipart API can stream the
+  /// file through `put`.
+  async fn put_multipart(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      self.put(key, file_path, metadata, log).await
+  }
+
+  /// Downloads an object to `destination`, via `temp_path` so readers never see a
+  /// half-written file.
+  async fn get(&self, key: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject>;
+
+  /// Deletes an object. Deleting one that doesn't exist succeeds.
+  async fn delete(&self, key: &str) -> Result<()>;
+
+  /// Copies an object, keeping its metadata.
+  async fn copy(&self, from: &str, to: &str) -> Result<()>;
+
+  /// Replaces an object's user metadata without changing its content.
+  async fn set_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<()>;
+
+  /// Whether the backend keeps old versions of deleted and overwritten objects.
+  async fn versioning_enabled(&self) -> Result<bool> {
+      Ok(false)
+  }
+
+  /// Whether a backend kept from an earlier sync can still be used. Backends that hold
+  /// a connection open check that the server hasn't dropped it.
+  async fn still_connected(&self) -> bool {
+      true
+  }
+}
+
+/// The local modification time an object's metadata carries. Backends that store
+/// plain files have nowhere to keep other metadata, so they give the file this time
+/// and drop the rest.
+pub fn modified_from(metadata: &HashMap<String, String>) -> Option<SystemTime> {
+  metadata.get(MTIME_METADATA_KEY)
+      .and_then(|nanos| nanos.parse::<u64>().ok())
+      .map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+/// The folder a listing prefix points into, e.g. `photos/2025` for `photos/2025/a`.
+/// Backends that walk folders to list them only need to walk that one.
+pub fn folder_of(prefix: &str) -> &str {
+  prefix.rsplit_once('/').map(|(folder, _)| folder).unwrap_or("")
+}
+
+/// Creates the backend named by a folder's `service` setting, retrying requests that
+/// fail in passing. Fails if it isn't one IceBucket supports, its settings can't work
+/// or it can't be reached.
+pub async fn backend_for(sync_settings: &SyncSettings) -> Result<Box<dyn StorageBackend>> {
+  let backend: Box<dyn StorageBackend> = match sync_settings.service.as_str() {
+      "s3" => Box::new(s3::S3Backend::new(sync_settings).await),
+      "local" => Box::new(local::LocalBackend::new(sync_settings)),
+      "sftp" => Box::new(sftp::SftpBackend::connect(sync_settings).await?),
+      "ftp" => Box::new(ftp::FtpBackend::connect(sync_settings).await?),
+      "azure" => Box::new(azure::AzureBackend::new(sync_settings)?),
+      "gcs" => Box::new(gcs::GcsBackend::new(sync_settings)?),
+      "webdav" => Box::new(webdav::WebDavBackend::new(sync_settings)?),
+      other => return Err(IceBucketError::Config(format!("unknown service {:?}", other))),
+  };
+  Ok(Box::new(retry::RetryingBackend::new(backend)))
+}
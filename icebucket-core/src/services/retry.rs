@@ -0,0 +1,206 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use crate::{Log, VERBOSE};
+use crate::error::Result;
+use super::{RemoteObject, StorageBackend, UploadedObject};
+
+/// Attempts at a single request before its failure is handed back to the sync.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The wait before the second attempt; it doubles with each one after that.
+const FIRST_DELAY: Duration = Duration::from_millis(500);
+
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Wraps a backend so a request that fails in a way that may pass, such as a dropped
+/// connection, a 5xx or being throttled, is tried again after a growing wait.
+/// Anything else, like rejected credentials or a missing bucket, fails straight away.
+pub struct RetryingBackend {
+  inner: Box<dyn StorageBackend>,
+}
+
+impl RetryingBackend {
+  pub fn new(inner: Box<dyn StorageBackend>) -> Self {
+      RetryingBackend { inner }
+  }
+
+  /// Waits before the next attempt and returns true if `result` failed in a way
+  /// worth retrying and attempts remain.
+  async fn should_retry<T>(&self, result: &Result<T>, attempt: &mut u32) -> bool {
+      let Err(e) = result else { return false };
+      if !e.is_transient() || *attempt >= MAX_ATTEMPTS {
+          return false;
+      }
+      let delay = backoff(FIRST_DELAY, MAX_DELAY, *attempt);
+      if VERBOSE.load(Ordering::Relaxed) {
+          println!("{}; trying again in {:.1?}", e, delay);
+      }
+      tokio::time::sleep(delay).await;
+      *attempt += 1;
+      true
+  }
+}
+
+/// How long to wait after `attempt` failed: doubling from `first` up to `max`, with
+/// up to half of it random so clients that failed together don't retry together.
+pub fn backoff(first: Duration, max: Duration, attempt: u32) -> Duration {
+  let ceiling = first.saturating_mul(1 << attempt.saturating_sub(1).min(20)).min(max);
+  let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+  ceiling.mul_f64(1.0 - jitter / 2.0)
+}
+
+#[async_trait]
+impl StorageBackend for RetryingBackend {
+  fn name(&self) -> &'static str {
+      self.inner.name()
+  }
+
+  async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+      let mut attempt = 1;
+      loop {
+          let result = self.inner.list(prefix).await;
+          if !self.should_retry(&result, &mut attempt).await {
+              return result;
+          }
+      }
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<RemoteObject>> {
+      let mut attempt = 1;
+      loop {
+          let result = self.inner.stat(key).await;
+          if !self.should_retry(&result, &mut attempt).await {
+              return result;
+          }
+      }
+  }
+
+  async fn put(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let mut attempt = 1;
+      loop {
+          let result = self.inner.put(key, file_path, metadata, log).await;
+          if !self.should_retry(&result, &mut attempt).await {
+              return result;
+          }
+      }
+  }
+
+  async fn put_multipart(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let mut attempt = 1;
+      loop {
+          let result = self.inner.put_multipart(key, file_path, metadata, log).await;
+          if !self.should_retry(&result, &mut attempt).await {
+              return result;
+          }
+      }
+  }
+
+  async fn get(&self, key: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject> {
+      let mut attempt = 1;
+      loop {
+          let result = self.inner.get(key, destination, temp_path, log).await;
+          if !self.should_retry(&result, &mut attempt).await {
+              return result;
+          }
+      }
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+      let mut attempt = 1;
+      loop {
+          let result = self.inner.delete(key).await;
+          if !self.should_retry(&result, &mut attempt).await {
+              return result;
+          }
+      }
+  }
+
+  async fn copy(&self, from: &str, to: &str) -> Result<()> {
+      let mut attempt = 1;
+      loop {
+          let result = self.inner.copy(from, to).await;
+          if !self.should_retry(&result, &mut attempt).await {
+              return result;
+          }
+      }
+  }
+
+  async fn set_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<()> {
+      let mut attempt = 1;
+      loop {
+          let result = self.inner.set_metadata(key, metadata).await;
+          if !self.should_retry(&result, &mut attempt).await {
+              return result;
+          }
+      }
+  }
+
+  async fn versioning_enabled(&self) -> Result<bool> {
+      let mut attempt = 1;
+      loop {
+          let result = self.inner.versioning_enabled().await;
+          if !self.should_retry(&result, &mut attempt).await {
+              return result;
+          }
+      }
+  }
+
+  async fn still_connected(&self) -> bool {
+      self.inner.still_connected().await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::error::IceBucketError;
+  use crate::testing::FakeBackend;
+  use std::sync::Mutex;
+
+  #[test]
+  fn backoff_doubles_up_to_the_cap_with_at_most_half_taken_off() {
+      let first = Duration::from_millis(100);
+      let max = Duration::from_secs(1);
+      for (attempt, ceiling) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+          let ceiling = Duration::from_millis(ceiling);
+          for _ in 0..20 {
+              let delay = backoff(first, max, attempt);
+              assert!(delay <= ceiling && delay >= ceiling / 2, "attempt {}: {:?}", attempt, delay);
+          }
+      }
+  }
+
+  /// Retries `stat` on a backend that fails with each of `failures` in turn and
+  /// returns how many requests it made.
+  async fn stat_calls_after(failures: Vec<IceBucketError>) -> (Result<Option<RemoteObject>>, u32) {
+      let inner = FakeBackend { stat_failures: Mutex::new(failures), ..FakeBackend::default() };
+      let calls = inner.stat_calls.clone();
+      let result = RetryingBackend::new(Box::new(inner)).stat("a.txt").await;
+      (result, calls.load(Ordering::Relaxed))
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn transient_failures_are_retried_until_attempts_run_out() {
+      let throttled = || IceBucketError::Throttled("Listing".to_string());
+
+      let (result, calls) = stat_calls_after(vec![throttled(), throttled()]).await;
+      assert!(matches!(result, Ok(None)));
+      assert_eq!(calls, 3);
+
+      let (result, calls) = stat_calls_after((0..10).map(|_| throttled()).collect()).await;
+      assert!(matches!(result, Err(IceBucketError::Throttled(_))));
+      assert_eq!(calls, MAX_ATTEMPTS);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn other_failures_are_not_retried() {
+      let (result, calls) = stat_calls_after(vec![IceBucketError::Auth("Listing".to_string())]).await;
+      assert!(matches!(result, Err(IceBucketError::Auth(_))));
+      assert_eq!(calls, 1);
+  }
+}
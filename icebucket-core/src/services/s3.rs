@@ -1,113 +1,490 @@
-use aws_sdk_s3::Client;
+use aws_sdk_s3::{Client, config::Region};
+use aws_config::BehaviorVersion;
+use aws_sdk_s3::config::{Credentials, RequestChecksumCalculation, ResponseChecksumValidation};
+use aws_sdk_s3::config::http::HttpResponse;
+use aws_sdk_s3::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_config::meta::region::RegionProviderChain;
+use std::collections::HashMap;
 use std::fs;
-use aws_sdk_s3::primitives::ByteStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use aws_sdk_s3::operation::complete_multipart_upload::CompleteMultipartUploadOutput;
+use aws_sdk_s3::primitives::{ByteStream, Length};
+use aws_sdk_s3::types::{BucketVersioningStatus, CompletedMultipartUpload, CompletedPart, MetadataDirective, ObjectCannedAcl};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use crate::error::{IceBucketError, Result};
 use crate::logger::{LogEntry, Log};
+use crate::{SyncSettings, VERBOSE};
+use super::{RemoteObject, StorageBackend, UploadedObject};
 
-pub async fn service_s3_check(client: &Client, bucket: &str, s3_path: &str) -> bool {
-  let objects = client.list_objects_v2()
+/// Syncs to an S3 bucket, or a bucket on an S3-compatible service when `endpoint`
+/// is set.
+pub struct S3Backend {
+  client: Client,
+  bucket: String,
+  /// Make uploaded objects publicly readable.
+  public: bool,
+  /// Set once the service has refused an ACL, so it isn't asked again for every file.
+  acls_unsupported: AtomicBool,
+  /// How many parts of a multipart upload are sent at once.
+  part_concurrency: usize,
+}
+
+impl S3Backend {
+  pub async fn new(sync_settings: &SyncSettings) -> Self {
+      S3Backend {
+          client: service_s3_client(sync_settings).await,
+          bucket: sync_settings.bucket.clone(),
+          public: sync_settings.public,
+          acls_unsupported: AtomicBool::new(false),
+          part_concurrency: sync_settings.part_concurrency,
+      }
+  }
+
+  /// Makes an object publicly readable. Some S3-compatible services (R2, for one) and
+  /// buckets with object ownership enforced don't take ACLs; the object is then left
+  /// as it is, and public access has to be granted through a bucket policy instead.
+  /// A refusal, such as a missing `s3:PutObjectAcl` permission, is an error like any
+  /// other, so it's reported rather than leaving uploads private for good.
+  async fn make_public(&self, key: &str) -> Result<()> {
+      if self.acls_unsupported.load(Ordering::Relaxed) {
+          return Ok(());
+      }
+      let result = self.client.put_object_acl()
+          .bucket(&self.bucket)
+          .key(key)
+          .acl(ObjectCannedAcl::PublicRead)
+          .send()
+          .await;
+      match result {
+          Ok(_) => Ok(()),
+          Err(e) if refuses_acls(e.code()) => {
+              self.acls_unsupported.store(true, Ordering::Relaxed);
+              eprintln!(
+                  "Bucket {} doesn't accept ACLs ({}), so uploads won't be made public; use a bucket policy instead",
+                  self.bucket,
+                  e.code().unwrap_or("unknown error"),
+              );
+              Ok(())
+          }
+          Err(e) => Err(s3_error(format!("Making {} public", key), e)),
+      }
+  }
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+  fn name(&self) -> &'static str {
+      "S3"
+  }
+
+  async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+      service_s3_list(&self.client, &self.bucket, prefix).await
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<RemoteObject>> {
+      service_s3_head(&self.client, &self.bucket, key).await
+  }
+
+  async fn put(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let uploaded = service_s3_upload(&self.client, &self.bucket, key, file_path, metadata, log).await?;
+      if self.public {
+          self.make_public(key).await?;
+      }
+      Ok(uploaded)
+  }
+
+  async fn put_multipart(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let uploaded = service_s3_multipart_upload(&self.client, &self.bucket, key, file_path, metadata, self.part_concurrency, log).await?;
+      if self.public {
+          self.make_public(key).await?;
+      }
+      Ok(uploaded)
+  }
+
+  async fn get(&self, key: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject> {
+      service_s3_download(&self.client, &self.bucket, key, destination, temp_path, log).await
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+      service_s3_delete(&self.client, &self.bucket, key).await
+  }
+
+  async fn copy(&self, from: &str, to: &str) -> Result<()> {
+      service_s3_copy(&self.client, &self.bucket, from, to, self.part_concurrency).await
+  }
+
+  async fn set_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<()> {
+      service_s3_set_metadata(&self.client, &self.bucket, key, metadata).await?;
+      if self.public {
+          // Copying an object onto itself resets its ACL
+          self.make_public(key).await?;
+      }
+      Ok(())
+  }
+
+  async fn versioning_enabled(&self) -> Result<bool> {
+      service_s3_versioning_enabled(&self.client, &self.bucket).await
+  }
+}
+
+/// Creates a client for AWS, or for the S3-compatible service at `endpoint` (MinIO,
+/// Cloudflare R2, Wasabi, Backblaze B2, DigitalOcean Spaces and the like).
+pub async fn service_s3_client(sync_settings: &SyncSettings) -> Client {
+  let custom_endpoint = !sync_settings.endpoint.is_empty();
+  let region_provider = if custom_endpoint {
+      // Most S3-compatible services ignore the region, but requests still have to be signed for one
+      let region = if sync_settings.region.is_empty() { "us-east-1".to_string() } else { sync_settings.region.clone() };
+      RegionProviderChain::first_try(Region::new(region))
+  } else {
+      RegionProviderChain::default_provider().or_else(Region::new(sync_settings.region.clone()))
+  };
+  let config = aws_config::defaults(BehaviorVersion::latest())
+      .region(region_provider)
+      .credentials_provider(Credentials::new(
+          sync_settings.access_key.clone(),
+          sync_settings.secret_key.clone(),
+          None,
+          None,
+          "default",
+      ))
+      .load()
+      .await;
+
+  let mut s3_config = aws_sdk_s3::config::Builder::from(&config).force_path_style(sync_settings.force_path_style);
+  if custom_endpoint {
+      s3_config = s3_config
+          .endpoint_url(&sync_settings.endpoint)
+          // Not every S3-compatible service understands the CRC checksums the SDK adds by default
+          .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+          .response_checksum_validation(ResponseChecksumValidation::WhenRequired);
+  }
+  Client::from_conf(s3_config.build())
+}
+
+/// Whether an error code from setting an ACL means the bucket takes no ACLs at all.
+fn refuses_acls(code: Option<&str>) -> bool {
+  matches!(code, Some("AccessControlListNotSupported" | "NotImplemented"))
+}
+
+/// Classifies a failed request by how it failed and the error code S3 returned,
+/// falling back to the HTTP status for responses without a body, such as HEAD's.
+fn s3_error<E: ProvideErrorMetadata + std::error::Error + 'static>(action: String, e: SdkError<E, HttpResponse>) -> IceBucketError {
+  let message = format!("{}: {}", action, DisplayErrorContext(&e));
+  match &e {
+      SdkError::ConstructionFailure(_) => return IceBucketError::Config(message),
+      SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => return IceBucketError::Network(message),
+      _ => {}
+  }
+  match e.code() {
+      Some("AccessDenied" | "InvalidAccessKeyId" | "SignatureDoesNotMatch" | "ExpiredToken" | "InvalidToken") => IceBucketError::Auth(message),
+      // Every file would fail the same way
+      Some("NoSuchBucket" | "PermanentRedirect" | "AuthorizationHeaderMalformed") => IceBucketError::Config(message),
+      Some("NoSuchKey" | "NoSuchUpload" | "NotFound") => IceBucketError::NotFound(message),
+      Some("SlowDown" | "Throttling" | "ThrottlingException" | "RequestLimitExceeded" | "TooManyRequests") => IceBucketError::Throttled(message),
+      // The connection stalled partway through a request
+      Some("RequestTimeout") => IceBucketError::Network(message),
+      _ => match e.raw_response() {
+          Some(response) => IceBucketError::from_status(response.status().as_u16(), message),
+          None => IceBucketError::Network(message),
+      },
+  }
+}
+
+/// Fetches an object's size, ETag and user metadata without downloading it.
+/// Returns `None` if the object doesn't exist.
+pub async fn service_s3_head(client: &Client, bucket: &str, s3_path: &str) -> Result<Option<RemoteObject>> {
+  match client.head_object().bucket(bucket).key(s3_path).send().await {
+      Ok(output) => Ok(Some(RemoteObject {
+          key: s3_path.to_string(),
+          size: output.content_length().unwrap_or(0) as u64,
+          e_tag: output.e_tag().map(str::to_string),
+          version_id: output.version_id().map(str::to_string),
+          last_modified: output.last_modified().and_then(|t| SystemTime::try_from(*t).ok()),
+          metadata: output.metadata().cloned().unwrap_or_default(),
+      })),
+      Err(e) if e.as_service_error().map(|e| e.is_not_found()).unwrap_or(false) => Ok(None),
+      Err(e) => Err(s3_error(format!("Checking {}", s3_path), e)),
+  }
+}
+
+/// Lists every object under `prefix`, following continuation tokens past the
+/// 1000-key page limit.
+pub async fn service_s3_list(client: &Client, bucket: &str, prefix: &str) -> Result<Vec<RemoteObject>> {
+  let mut objects = Vec::new();
+  let mut pages = client.list_objects_v2()
       .bucket(bucket)
-      .prefix(s3_path)
+      .prefix(prefix)
+      .into_paginator()
+      .send();
+
+  while let Some(page) = pages.next().await {
+      let page = page.map_err(|e| s3_error(format!("Listing {}", bucket), e))?;
+      for object in page.contents() {
+          let Some(key) = object.key() else { continue };
+          objects.push(RemoteObject {
+              key: key.to_string(),
+              size: object.size().unwrap_or(0) as u64,
+              e_tag: object.e_tag().map(str::to_string),
+              version_id: None,
+              last_modified: object.last_modified().and_then(|t| SystemTime::try_from(*t).ok()),
+              metadata: HashMap::new(),
+          });
+      }
+  }
+
+  Ok(objects)
+}
+
+/// Downloads an object to `destination`. The body is streamed into a temp file next
+/// to the destination which is only renamed into place once complete, so readers
+/// never see a half-written file.
+pub async fn service_s3_download(client: &Client, bucket: &str, s3_path: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject> {
+  use tokio::io::AsyncWriteExt;
+
+  let output = client.get_object()
+      .bucket(bucket)
+      .key(s3_path)
       .send()
       .await
-      .expect("Failed to list objects");
+      .map_err(|e| s3_error(format!("Downloading {}", s3_path), e))?;
+
+  let file_path = destination.to_string_lossy().to_string();
+  let total_bytes = output.content_length().unwrap_or(0) as u64;
+  log.add_entry(LogEntry::new(file_path.clone(), bucket.to_string(), s3_path.to_string(), total_bytes));
 
-  if let Some(contents) = objects.contents {
-      for object in contents {
-          if object.key().unwrap() == s3_path {
-              return true;
+  let remote = RemoteObject {
+      key: s3_path.to_string(),
+      size: total_bytes,
+      e_tag: output.e_tag().map(str::to_string),
+      version_id: output.version_id().map(str::to_string),
+      last_modified: output.last_modified().and_then(|t| SystemTime::try_from(*t).ok()),
+      metadata: output.metadata().cloned().unwrap_or_default(),
+  };
+
+  if let Some(parent) = destination.parent() {
+      fs::create_dir_all(parent).map_err(|e| IceBucketError::io(parent.display(), e))?;
+  }
+  let temp_error = |e| IceBucketError::io(temp_path.display(), e);
+  let mut file = tokio::fs::File::create(temp_path).await.map_err(temp_error)?;
+  let mut body = output.body;
+  while let Some(bytes) = body.try_next().await.map_err(|e| IceBucketError::Network(format!("Downloading {}: {}", s3_path, e)))? {
+      file.write_all(&bytes).await.map_err(temp_error)?;
+  }
+  file.sync_all().await.map_err(temp_error)?;
+  drop(file);
+  fs::rename(temp_path, destination).map_err(|e| IceBucketError::io(destination.display(), e))?;
+
+  log.update_entry(&file_path, total_bytes);
+  Ok(remote)
+}
+
+pub async fn service_s3_delete(client: &Client, bucket: &str, s3_path: &str) -> Result<()> {
+  client.delete_object()
+      .bucket(bucket)
+      .key(s3_path)
+      .send()
+      .await
+      .map_err(|e| s3_error(format!("Deleting {}", s3_path), e))?;
+  Ok(())
+}
+
+/// Copies an object within the bucket, keeping its metadata. A single copy request
+/// takes objects of up to 5 GB; larger ones are copied a part at a time, up to
+/// `concurrency` parts at once.
+pub async fn service_s3_copy(client: &Client, bucket: &str, from: &str, to: &str, concurrency: usize) -> Result<()> {
+  let source = service_s3_head(client, bucket, from).await?
+      .ok_or_else(|| IceBucketError::NotFound(format!("Copying {}", from)))?;
+  if source.size <= MAX_COPY_SIZE {
+      client.copy_object()
+          .bucket(bucket)
+          .copy_source(format!("{}/{}", bucket, encode_key(from)))
+          .key(to)
+          .send()
+          .await
+          .map_err(|e| s3_error(format!("Copying {} to {}", from, to), e))?;
+      return Ok(());
+  }
+
+  // A multipart copy starts out without metadata, so it's carried over by hand
+  let create_multipart_upload = client
+      .create_multipart_upload()
+      .bucket(bucket)
+      .key(to)
+      .set_metadata(Some(source.metadata.clone()))
+      .send()
+      .await
+      .map_err(|e| s3_error(format!("Starting copy of {} to {}", from, to), e))?;
+  let upload_id = create_multipart_upload.upload_id()
+      .ok_or_else(|| IceBucketError::Service(format!("Starting copy of {} to {}: no upload ID returned", from, to)))?;
+  let started = StartedUpload::new(client, bucket, to, upload_id);
+
+  let part_size = part_size_for(source.size).max(COPY_PART_SIZE);
+  let num_parts = source.size.div_ceil(part_size);
+  let copy_source = format!("{}/{}", bucket, encode_key(from));
+  let result = stream::iter(1..=num_parts)
+      .map(|part_number| {
+          let copy_source = &copy_source;
+          async move {
+              let first = (part_number - 1) * part_size;
+              let last = (first + part_size).min(source.size) - 1;
+              let output = client.upload_part_copy()
+                  .bucket(bucket)
+                  .key(to)
+                  .upload_id(upload_id)
+                  .part_number(part_number as i32)
+                  .copy_source(copy_source)
+                  .copy_source_range(format!("bytes={}-{}", first, last))
+                  .send()
+                  .await
+                  .map_err(|e| s3_error(format!("Copying part {} of {} to {}", part_number, from, to), e))?;
+              let e_tag = output.copy_part_result().and_then(|result| result.e_tag())
+                  .ok_or_else(|| IceBucketError::Service(format!("Copying part {} of {} to {}: no ETag returned", part_number, from, to)))?;
+              Ok(CompletedPart::builder()
+                  .part_number(part_number as i32)
+                  .e_tag(e_tag)
+                  .build())
           }
+      })
+      .buffer_unordered(concurrency.max(1))
+      .try_collect()
+      .await;
+  let result = match result {
+      Ok(completed_parts) => complete_multipart_upload(client, bucket, to, upload_id, completed_parts).await,
+      Err(e) => Err(e),
+  };
+  started.settle(result.is_ok()).await;
+  result.map(|_| ())
+}
+
+/// Replaces an object's user metadata by copying it onto itself.
+pub async fn service_s3_set_metadata(client: &Client, bucket: &str, s3_path: &str, metadata: &HashMap<String, String>) -> Result<()> {
+  client.copy_object()
+      .bucket(bucket)
+      .copy_source(format!("{}/{}", bucket, encode_key(s3_path)))
+      .key(s3_path)
+      .metadata_directive(MetadataDirective::Replace)
+      .set_metadata(Some(metadata.clone()))
+      .send()
+      .await
+      .map_err(|e| s3_error(format!("Updating metadata of {}", s3_path), e))?;
+  Ok(())
+}
+
+pub async fn service_s3_versioning_enabled(client: &Client, bucket: &str) -> Result<bool> {
+  let output = client.get_bucket_versioning()
+      .bucket(bucket)
+      .send()
+      .await
+      .map_err(|e| s3_error(format!("Checking versioning of {}", bucket), e))?;
+  Ok(output.status() == Some(&BucketVersioningStatus::Enabled))
+}
+
+/// Percent-encodes a key for use in `x-amz-copy-source`, leaving `/` separators intact.
+fn encode_key(key: &str) -> String {
+  let mut encoded = String::new();
+  for byte in key.bytes() {
+      match byte {
+          b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+          _ => encoded.push_str(&format!("%{:02X}", byte)),
       }
   }
-
-  false
+  encoded
 }
 
-pub async fn service_s3_upload(client: &Client, bucket: &str, s3_path: &str, file_path: &str, log: &mut Log) {
-  let file_content = fs::read(file_path).expect("Unable to read file content");
+pub async fn service_s3_upload(client: &Client, bucket: &str, s3_path: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+  let file_content = fs::read(file_path).map_err(|e| IceBucketError::io(file_path, e))?;
   let total_bytes = file_content.len() as u64;
   log.add_entry(LogEntry::new(file_path.to_string(), bucket.to_string(), s3_path.to_string(), total_bytes));
 
-  client.put_object()
+  let output = client.put_object()
       .bucket(bucket)
       .key(s3_path)
+      .set_metadata(Some(metadata.clone()))
       .body(ByteStream::from(file_content))
       .send()
       .await
-      .expect("Failed to upload file");
+      .map_err(|e| s3_error(format!("Uploading {}", s3_path), e))?;
 
   log.update_entry(file_path, total_bytes);
+  Ok(UploadedObject {
+      e_tag: output.e_tag().map(str::to_string),
+      version_id: output.version_id().map(str::to_string),
+  })
 }
 
-pub async fn service_s3_multipart_upload(client: &Client, bucket: &str, key: &str, file_path: &str, log: &mut Log) {
-  use aws_sdk_s3::types::CompletedMultipartUpload;
-  use aws_sdk_s3::types::CompletedPart;
-  use tokio::fs::File;
-  use tokio::io::AsyncReadExt;
-
-  let mut file = File::open(file_path).await.unwrap();
-  let file_size = file.metadata().await.unwrap().len();
-  let part_size = 5 * 1024 * 1024; // 5MB
-  let num_parts = (file_size + part_size - 1) / part_size;
-
-  println!("File size: {}, Part size: {}, Number of parts: {}", file_size, part_size, num_parts);
-
+/// Uploads a file in parts, up to `concurrency` of them at a time. If any part fails,
+/// or the upload is cut short, it's aborted, so the parts already sent don't linger
+/// in the bucket (and on the bill).
+pub async fn service_s3_multipart_upload(client: &Client, bucket: &str, key: &str, file_path: &str, metadata: &HashMap<String, String>, concurrency: usize, log: &Log) -> Result<UploadedObject> {
+  let file_size = fs::metadata(file_path).map_err(|e| IceBucketError::io(file_path, e))?.len();
+  if file_size > MAX_OBJECT_SIZE {
+      return Err(IceBucketError::Service(format!("{} is larger than the 5 TB S3 allows for an object", file_path)));
+  }
   log.add_entry(LogEntry::new(file_path.to_string(), bucket.to_string(), key.to_string(), file_size));
 
   let create_multipart_upload = client
       .create_multipart_upload()
       .bucket(bucket)
       .key(key)
+      .set_metadata(Some(metadata.clone()))
       .send()
       .await
-      .unwrap();
+      .map_err(|e| s3_error(format!("Starting upload of {}", key), e))?;
 
-  let upload_id = create_multipart_upload.upload_id().unwrap().to_string();
-  let mut completed_parts = Vec::new();
+  let upload_id = create_multipart_upload.upload_id()
+      .ok_or_else(|| IceBucketError::Service(format!("Starting upload of {}: no upload ID returned", key)))?;
+  let started = StartedUpload::new(client, bucket, key, upload_id);
 
-  for part_number in 1..=num_parts {
-      let mut buffer = vec![0; part_size as usize];
-      let mut total_bytes_read = 0;
+  let upload = MultipartUpload {
+      client,
+      bucket,
+      key,
+      file_path,
+      upload_id,
+      file_size,
+      part_size: part_size_for(file_size),
+  };
+  let result = upload.upload_parts(concurrency, log).await;
+  started.settle(result.is_ok()).await;
+  let output = result?;
 
-      while total_bytes_read < part_size as usize {
-          let bytes_read = file.read(&mut buffer[total_bytes_read..]).await.unwrap();
-          if bytes_read == 0 {
-              break;
-          }
-          total_bytes_read += bytes_read;
-      }
+  log.update_entry(file_path, file_size);
+  Ok(UploadedObject {
+      e_tag: output.e_tag().map(str::to_string),
+      version_id: output.version_id().map(str::to_string),
+  })
+}
 
-      buffer.truncate(total_bytes_read);
+/// The most parts S3 takes for one upload.
+const MAX_PARTS: u64 = 10_000;
 
-      println!("Part number: {}, Bytes read: {}", part_number, total_bytes_read);
+/// The smallest part S3 takes, other than the last.
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
 
-      // Ensure that all parts except the last one are at least 5MB
-      if part_number < num_parts && total_bytes_read < part_size as usize {
-          panic!("Part size is smaller than the minimum allowed size");
-      }
+/// The largest object S3 takes.
+const MAX_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024 * 1024;
 
-      let upload_part = client
-          .upload_part()
-          .bucket(bucket)
-          .key(key)
-          .upload_id(&upload_id)
-          .part_number(part_number as i32)
-          .body(buffer.into())
-          .send()
-          .await
-          .unwrap();
+/// The part size for a file: 5 MB, or as many whole MB as it takes to stay within
+/// `MAX_PARTS`, for files over about 48 GB.
+fn part_size_for(file_size: u64) -> u64 {
+  const MB: u64 = 1024 * 1024;
+  file_size.div_ceil(MAX_PARTS).div_ceil(MB).saturating_mul(MB).max(MIN_PART_SIZE)
+}
 
-      completed_parts.push(
-          CompletedPart::builder()
-              .part_number(part_number as i32)
-              .e_tag(upload_part.e_tag().unwrap().to_string())
-              .build(),
-      );
+/// The largest object a single CopyObject request takes.
+const MAX_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
 
-      if let Some(entry) = log.entries.iter_mut().find(|e| e.file_path == file_path) {
-          entry.completed_bytes += total_bytes_read as u64;
-      }
-  }
+/// The smallest part a copy is split into. Parts are copied within S3, so fewer,
+/// larger parts just mean fewer requests.
+const COPY_PART_SIZE: u64 = 1024 * 1024 * 1024;
 
+/// Finishes a multipart upload or copy from its parts.
+async fn complete_multipart_upload(client: &Client, bucket: &str, key: &str, upload_id: &str, mut completed_parts: Vec<CompletedPart>) -> Result<CompleteMultipartUploadOutput> {
+  // Parts finish in any order, but have to be listed in order
+  completed_parts.sort_by_key(|part| part.part_number());
   let completed_multipart_upload = CompletedMultipartUpload::builder()
       .set_parts(Some(completed_parts))
       .build();
@@ -120,7 +497,165 @@ pub async fn service_s3_multipart_upload(client: &Client, bucket: &str, key: &st
       .multipart_upload(completed_multipart_upload)
       .send()
       .await
-      .unwrap();
+      .map_err(|e| s3_error(format!("Completing upload of {}", key), e))
+}
 
-  log.update_entry(file_path, file_size);
-}
\ No newline at end of file
+/// Abandons a failed multipart upload or copy, so the parts already sent don't
+/// linger in the bucket (and on the bill).
+async fn abort_multipart_upload(client: &Client, bucket: &str, key: &str, upload_id: &str) {
+  let abort = client.abort_multipart_upload()
+      .bucket(bucket)
+      .key(key)
+      .upload_id(upload_id)
+      .send()
+      .await;
+  if let Err(e) = abort {
+      eprintln!("Failed to abort upload of {}: {}", key, DisplayErrorContext(&e));
+  }
+}
+
+/// A multipart upload or copy that has been started and is aborted unless `settle`
+/// is told it completed. That includes when the future driving it is dropped halfway,
+/// e.g. when a failure elsewhere ends the sync: the abort then runs as a task of
+/// its own.
+struct StartedUpload {
+  client: Client,
+  bucket: String,
+  key: String,
+  upload_id: String,
+  settled: bool,
+}
+
+impl StartedUpload {
+  fn new(client: &Client, bucket: &str, key: &str, upload_id: &str) -> Self {
+      StartedUpload {
+          client: client.clone(),
+          bucket: bucket.to_string(),
+          key: key.to_string(),
+          upload_id: upload_id.to_string(),
+          settled: false,
+      }
+  }
+
+  /// Aborts the upload if it didn't complete.
+  async fn settle(mut self, completed: bool) {
+      if !completed {
+          abort_multipart_upload(&self.client, &self.bucket, &self.key, &self.upload_id).await;
+      }
+      self.settled = true;
+  }
+}
+
+impl Drop for StartedUpload {
+  fn drop(&mut self) {
+      if self.settled {
+          return;
+      }
+      let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+          eprintln!("Unable to abort upload of {}", self.key);
+          return;
+      };
+      let (client, bucket, key, upload_id) = (self.client.clone(), std::mem::take(&mut self.bucket), std::mem::take(&mut self.key), std::mem::take(&mut self.upload_id));
+      runtime.spawn(async move {
+          abort_multipart_upload(&client, &bucket, &key, &upload_id).await;
+      });
+  }
+}
+
+/// A multipart upload that has been started.
+struct MultipartUpload<'a> {
+  client: &'a Client,
+  bucket: &'a str,
+  key: &'a str,
+  file_path: &'a str,
+  upload_id: &'a str,
+  file_size: u64,
+  part_size: u64,
+}
+
+impl MultipartUpload<'_> {
+  async fn upload_parts(&self, concurrency: usize, log: &Log) -> Result<CompleteMultipartUploadOutput> {
+      let num_parts = self.file_size.div_ceil(self.part_size);
+      if VERBOSE.load(Ordering::Relaxed) {
+          println!("Uploading {} in {} parts of {} bytes", self.key, num_parts, self.part_size);
+      }
+
+      let completed_parts = stream::iter(1..=num_parts)
+          .map(|part_number| self.upload_part(part_number, log))
+          .buffer_unordered(concurrency.max(1))
+          .try_collect()
+          .await?;
+      complete_multipart_upload(self.client, self.bucket, self.key, self.upload_id, completed_parts).await
+  }
+
+  /// Uploads one part, numbered from 1. Parts are streamed from their place in the
+  /// file rather than read into memory first, so memory use stays the same however
+  /// large the parts and however many are in flight.
+  async fn upload_part(&self, part_number: u64, log: &Log) -> Result<CompletedPart> {
+      let offset = (part_number - 1) * self.part_size;
+      let length = self.part_size.min(self.file_size - offset);
+      let body = ByteStream::read_from()
+          .path(self.file_path)
+          .offset(offset)
+          .length(Length::Exact(length))
+          .build()
+          .await
+          // Fails if the file shrank since the upload started
+          .map_err(|e| IceBucketError::io(self.file_path, std::io::Error::other(e)))?;
+
+      let upload_part = self.client
+          .upload_part()
+          .bucket(self.bucket)
+          .key(self.key)
+          .upload_id(self.upload_id)
+          .part_number(part_number as i32)
+          .content_length(length as i64)
+          .body(body)
+          .send()
+          .await
+          .map_err(|e| s3_error(format!("Uploading part {} of {}", part_number, self.key), e))?;
+
+      let e_tag = upload_part.e_tag()
+          .ok_or_else(|| IceBucketError::Service(format!("Uploading part {} of {}: no ETag returned", part_number, self.key)))?;
+      log.add_progress(self.file_path, length);
+      Ok(CompletedPart::builder()
+          .part_number(part_number as i32)
+          .e_tag(e_tag)
+          .build())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MB: u64 = 1024 * 1024;
+
+  #[test]
+  fn part_size_for_stays_within_the_part_limit() {
+      for file_size in [0, 1, 5 * MB, 48 * 1024 * MB, 50_000 * MB, 50_000 * MB + 1, MAX_OBJECT_SIZE] {
+          let part_size = part_size_for(file_size);
+          assert!(part_size >= MIN_PART_SIZE, "{}", file_size);
+          assert_eq!(part_size % MB, 0, "{}", file_size);
+          assert!(file_size.div_ceil(part_size) <= MAX_PARTS, "{}", file_size);
+      }
+  }
+
+  #[test]
+  fn part_size_for_grows_only_past_the_part_limit() {
+      assert_eq!(part_size_for(5 * MB), MIN_PART_SIZE);
+      assert_eq!(part_size_for(48 * 1024 * MB), MIN_PART_SIZE);
+      assert_eq!(part_size_for(50_000 * MB), MIN_PART_SIZE);
+      assert_eq!(part_size_for(50_000 * MB + 1), 6 * MB);
+      assert_eq!(part_size_for(MAX_OBJECT_SIZE), 525 * MB);
+  }
+
+  #[test]
+  fn only_acl_refusals_turn_acls_off() {
+      assert!(refuses_acls(Some("AccessControlListNotSupported")));
+      assert!(refuses_acls(Some("NotImplemented")));
+      assert!(!refuses_acls(Some("AccessDenied")));
+      assert!(!refuses_acls(Some("InternalError")));
+      assert!(!refuses_acls(None));
+  }
+}
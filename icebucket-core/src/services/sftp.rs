@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use async_trait::async_trait;
+use russh::client::{self, Handle};
+use russh::keys::{HashAlg, PrivateKeyWithHashAlg, PublicKeyOrCertificate, check_known_hosts, check_known_hosts_path, load_secret_key};
+use russh_sftp::client::SftpSession;
+use russh_sftp::client::error::Error as SftpError;
+use russh_sftp::client::fs::Metadata;
+use russh_sftp::protocol::StatusCode;
+use crate::error::{IceBucketError, Result};
+use crate::logger::{LogEntry, Log};
+use crate::sync::TEMP_FILE_SUFFIX;
+use crate::SyncSettings;
+use super::{RemoteObject, StorageBackend, UploadedObject, folder_of, modified_from};
+
+const DEFAULT_PORT: u16 = 22;
+
+/// Syncs to a folder on an SFTP server. `endpoint` is the host, `access_key` the user
+/// name and `secret_key` the password (or the passphrase of `private_key`, if one is
+/// set). `bucket` is the folder on the server that keys are relative to.
+///
+/// SFTP has no user metadata and only keeps modification times to the second, so the
+/// size and modification time serve as the ETag and objects are never matched by hash.
+pub struct SftpBackend {
+  // The SSH connection has to outlive the SFTP channel running over it
+  connection: Handle<KnownHostsCheck>,
+  sftp: SftpSession,
+  host: String,
+  base_path: String,
+}
+
+impl SftpBackend {
+  /// Connects and logs in. Fails if the server can't be reached, isn't in
+  /// known_hosts or rejects the credentials.
+  pub async fn connect(sync_settings: &SyncSettings) -> Result<Self> {
+      let host = sync_settings.endpoint.clone();
+      let port = if sync_settings.port == 0 { DEFAULT_PORT } else { sync_settings.port };
+      let handler = KnownHostsCheck {
+          host: host.clone(),
+          port,
+          known_hosts: (!sync_settings.known_hosts.is_empty()).then(|| PathBuf::from(&sync_settings.known_hosts)),
+      };
+
+      let config = Arc::new(client::Config::default());
+      // A host key known_hosts doesn't vouch for is reported by the check itself
+      let mut connection = client::connect(config, (host.as_str(), port), handler).await
+          .map_err(|e| IceBucketError::Network(format!("Connecting to {}:{}: {}", host, port, e)))?;
+
+      let user = sync_settings.access_key.clone();
+      let auth = if sync_settings.private_key.is_empty() {
+          connection.authenticate_password(user, sync_settings.secret_key.clone()).await
+      } else {
+          let passphrase = (!sync_settings.secret_key.is_empty()).then_some(sync_settings.secret_key.as_str());
+          let key = load_secret_key(&sync_settings.private_key, passphrase)
+              .map_err(|e| IceBucketError::Config(format!("Loading private key {}: {}", sync_settings.private_key, e)))?;
+          let hash_alg = connection.best_supported_rsa_hash().await.ok().flatten().flatten();
+          connection.authenticate_publickey(user, PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg)).await
+      };
+      match auth {
+          Ok(result) if result.success() => {}
+          Ok(_) => return Err(IceBucketError::Auth(format!("SFTP login to {} as {} was rejected", host, sync_settings.access_key))),
+          Err(e) => return Err(IceBucketError::Network(format!("SFTP login to {}: {}", host, e))),
+      }
+
+      let channel = connection.channel_open_session().await
+          .map_err(|e| IceBucketError::Network(format!("Opening an SSH channel to {}: {}", host, e)))?;
+      channel.request_subsystem(true, "sftp").await
+          .map_err(|e| IceBucketError::Config(format!("{} doesn't provide SFTP: {}", host, e)))?;
+      let sftp = SftpSession::new(channel.into_stream()).await
+          .map_err(|e| sftp_error(format!("Starting an SFTP session with {}", host), e))?;
+
+      let base_path = sync_settings.bucket.trim_end_matches('/').to_string();
+      Ok(SftpBackend {
+          connection,
+          sftp,
+          host,
+          base_path: if base_path.is_empty() { ".".to_string() } else { base_path },
+      })
+  }
+
+  fn path_for(&self, key: &str) -> String {
+      format!("{}/{}", self.base_path, key)
+  }
+
+  /// Creates the folders leading up to `key`, like `mkdir -p`.
+  async fn create_parents(&self, key: &str) {
+      let Some((folders, _)) = key.rsplit_once('/') else { return };
+      let mut path = self.base_path.clone();
+      for folder in folders.split('/') {
+          path = format!("{}/{}", path, folder);
+          // Most already exist; one that can't be made fails the write that follows
+          let _ = self.sftp.create_dir(path.as_str()).await;
+      }
+  }
+
+  /// Replaces `key` with the fully written file at `temp_key`. SFTP's rename won't
+  /// overwrite an existing file, so the old one is removed first.
+  async fn replace(&self, temp_key: &str, key: &str) -> Result<()> {
+      let path = self.path_for(key);
+      match self.sftp.remove_file(path.as_str()).await {
+          Ok(()) => {}
+          Err(e) if is_not_found(&e) => {}
+          Err(e) => return Err(sftp_error(format!("Replacing {}", path), e)),
+      }
+      self.sftp.rename(self.path_for(temp_key), path.as_str()).await
+          .map_err(|e| sftp_error(format!("Moving {} into place", path), e))
+  }
+
+  /// Streams `source` into a new remote file at `temp_key`.
+  async fn write_remote(&self, source: &mut (impl tokio::io::AsyncRead + Unpin), temp_key: &str) -> Result<()> {
+      let temp_path = self.path_for(temp_key);
+      let mut destination = self.sftp.create(temp_path.as_str()).await
+          .map_err(|e| sftp_error(format!("Creating {}", temp_path), e))?;
+      tokio::io::copy(source, &mut destination).await
+          .map_err(|e| IceBucketError::Network(format!("Writing {}: {}", temp_path, e)))?;
+      tokio::io::AsyncWriteExt::shutdown(&mut destination).await
+          .map_err(|e| IceBucketError::Network(format!("Writing {}: {}", temp_path, e)))
+  }
+
+  /// Fails if the base path is missing. Listings take missing folders as empty, and a
+  /// base path that was renamed or unmounted must not look like every file was deleted.
+  async fn check_base_path(&self) -> Result<()> {
+      match self.sftp.metadata(self.base_path.clone()).await {
+          Ok(metadata) if metadata.is_dir() => Ok(()),
+          Ok(_) => Err(IceBucketError::Config(format!("{} on {} isn't a folder", self.base_path, self.host))),
+          Err(e) => Err(sftp_error(format!("Checking folder {} on {}", self.base_path, self.host), e)),
+      }
+  }
+
+  /// Walks `folder` and collects every file as an object keyed by its path below
+  /// the base path. Missing folders have no files.
+  async fn list_folder(&self, folder: String, objects: &mut Vec<RemoteObject>) -> Result<()> {
+      let mut pending = vec![folder];
+      while let Some(folder) = pending.pop() {
+          let path = if folder.is_empty() { self.base_path.clone() } else { self.path_for(&folder) };
+          let entries = match self.sftp.read_dir(path.as_str()).await {
+              Ok(entries) => entries,
+              Err(e) if is_not_found(&e) => continue,
+              Err(e) => return Err(sftp_error(format!("Listing {}", path), e)),
+          };
+          for entry in entries {
+              let name = entry.file_name();
+              if name == "." || name == ".." {
+                  continue;
+              }
+              let key = if folder.is_empty() { name } else { format!("{}/{}", folder, name) };
+              let metadata = entry.metadata();
+              if metadata.is_dir() {
+                  pending.push(key);
+              } else if metadata.is_regular() {
+                  objects.push(remote_object(&key, &metadata));
+              }
+          }
+      }
+      Ok(())
+  }
+}
+
+#[async_trait]
+impl StorageBackend for SftpBackend {
+  fn name(&self) -> &'static str {
+      "SFTP"
+  }
+
+  async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+      let folder = folder_of(prefix);
+      let mut objects = Vec::new();
+      self.list_folder(folder.to_string(), &mut objects).await?;
+      if objects.is_empty() {
+          self.check_base_path().await?;
+      }
+      objects.retain(|object| object.key.starts_with(prefix));
+      Ok(objects)
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<RemoteObject>> {
+      match self.sftp.metadata(self.path_for(key)).await {
+          Ok(metadata) if metadata.is_regular() => Ok(Some(remote_object(key, &metadata))),
+          Ok(_) => Ok(None),
+          Err(e) if is_not_found(&e) => Ok(None),
+          Err(e) => Err(sftp_error(format!("Checking {}", key), e)),
+      }
+  }
+
+  async fn put(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let total_bytes = std::fs::metadata(file_path).map(|metadata| metadata.len()).unwrap_or(0);
+      log.add_entry(LogEntry::new(file_path.to_string(), self.host.clone(), self.path_for(key), total_bytes));
+
+      self.create_parents(key).await;
+      let temp_key = format!("{}{}", key, TEMP_FILE_SUFFIX);
+      let mut source = tokio::fs::File::open(file_path).await.map_err(|e| IceBucketError::io(file_path, e))?;
+      self.write_remote(&mut source, &temp_key).await?;
+      self.set_metadata(&temp_key, metadata).await?;
+      self.replace(&temp_key, key).await?;
+
+      log.update_entry(file_path, total_bytes);
+      let written = self.stat(key).await?
+          .ok_or_else(|| IceBucketError::NotFound(format!("Uploaded file {} is missing", self.path_for(key))))?;
+      Ok(UploadedObject {
+          e_tag: written.e_tag,
+          version_id: None,
+      })
+  }
+
+  async fn get(&self, key: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject> {
+      let remote = self.stat(key).await?
+          .ok_or_else(|| IceBucketError::NotFound(format!("Downloading {}", key)))?;
+      let file_path = destination.to_string_lossy().to_string();
+      log.add_entry(LogEntry::new(file_path.clone(), self.host.clone(), self.path_for(key), remote.size));
+
+      if let Some(parent) = destination.parent() {
+          std::fs::create_dir_all(parent).map_err(|e| IceBucketError::io(parent.display(), e))?;
+      }
+      {
+          let mut source = self.sftp.open(self.path_for(key)).await
+              .map_err(|e| sftp_error(format!("Downloading {}", key), e))?;
+          let mut file = tokio::fs::File::create(temp_path).await.map_err(|e| IceBucketError::io(temp_path.display(), e))?;
+          tokio::io::copy(&mut source, &mut file).await
+              .map_err(|e| IceBucketError::Network(format!("Downloading {}: {}", key, e)))?;
+          file.sync_all().await.map_err(|e| IceBucketError::io(temp_path.display(), e))?;
+      }
+      std::fs::rename(temp_path, destination).map_err(|e| IceBucketError::io(destination.display(), e))?;
+
+      log.update_entry(&file_path, remote.size);
+      Ok(remote)
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+      match self.sftp.remove_file(self.path_for(key)).await {
+          Ok(()) => Ok(()),
+          Err(e) if is_not_found(&e) => Ok(()),
+          Err(e) => Err(sftp_error(format!("Deleting {}", key), e)),
+      }
+  }
+
+  /// SFTP can't copy on the server, so the content makes a round trip through here.
+  /// The copy gets the current time as its modification time, which the trash relies on.
+  async fn copy(&self, from: &str, to: &str) -> Result<()> {
+      self.create_parents(to).await;
+      let temp_key = format!("{}{}", to, TEMP_FILE_SUFFIX);
+      let mut source = self.sftp.open(self.path_for(from)).await
+          .map_err(|e| sftp_error(format!("Copying {}", from), e))?;
+      self.write_remote(&mut source, &temp_key).await?;
+      self.replace(&temp_key, to).await
+  }
+
+  async fn still_connected(&self) -> bool {
+      !self.connection.is_closed() && self.sftp.canonicalize(".").await.is_ok()
+  }
+
+  async fn set_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<()> {
+      let Some(modified) = modified_from(metadata) else { return Ok(()) };
+      let seconds = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0) as u32;
+      let attributes = Metadata {
+          atime: Some(seconds),
+          mtime: Some(seconds),
+          ..Metadata::empty()
+      };
+      // Some servers don't let users set times; the file is still fine without it
+      if let Err(e) = self.sftp.set_metadata(self.path_for(key), attributes).await {
+          eprintln!("Failed to set modified time on {}: {}", key, e);
+      }
+      Ok(())
+  }
+}
+
+/// Only accepts servers whose key is already in known_hosts (the user's own, or the
+/// file set in `known_hosts`), as `ssh` does with `StrictHostKeyChecking`.
+struct KnownHostsCheck {
+  host: String,
+  port: u16,
+  known_hosts: Option<PathBuf>,
+}
+
+impl client::Handler for KnownHostsCheck {
+  type Error = russh::Error;
+
+  async fn check_server_key(&mut self, server_public_key: &PublicKeyOrCertificate) -> std::result::Result<bool, Self::Error> {
+      let PublicKeyOrCertificate::PublicKey { key, .. } = server_public_key else {
+          eprintln!("{} presented a host certificate, which isn't supported", self.host);
+          return Ok(false);
+      };
+      let known = match &self.known_hosts {
+          Some(path) => check_known_hosts_path(&self.host, self.port, key, path),
+          None => check_known_hosts(&self.host, self.port, key),
+      };
+      match known {
+          Ok(true) => Ok(true),
+          Ok(false) => {
+              eprintln!(
+                  "Host key {} for {} isn't in known_hosts; connect once with ssh, or add it with ssh-keyscan",
+                  key.fingerprint(HashAlg::Sha256),
+                  self.host,
+              );
+              Ok(false)
+          }
+          Err(e) => {
+              eprintln!("Host key check for {} failed: {}", self.host, e);
+              Ok(false)
+          }
+      }
+  }
+}
+
+fn remote_object(key: &str, metadata: &Metadata) -> RemoteObject {
+  let modified = metadata.mtime.unwrap_or(0);
+  RemoteObject {
+      key: key.to_string(),
+      size: metadata.len(),
+      e_tag: Some(format!("{}-{}", metadata.len(), modified)),
+      version_id: None,
+      last_modified: metadata.mtime.map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime as u64)),
+      metadata: HashMap::new(),
+  }
+}
+
+/// Classifies a failed request by the status the server replied with. Permission
+/// errors are down to the one file's owner or mode, not the login, so they're left
+/// to fail that file alone.
+fn sftp_error(action: String, error: SftpError) -> IceBucketError {
+  let message = format!("{}: {}", action, error);
+  match &error {
+      SftpError::Status(status) => match status.status_code {
+          StatusCode::NoSuchFile => IceBucketError::NotFound(message),
+          StatusCode::NoConnection | StatusCode::ConnectionLost => IceBucketError::Network(message),
+          _ => IceBucketError::Service(message),
+      },
+      SftpError::IO(_) | SftpError::Timeout => IceBucketError::Network(message),
+      _ => IceBucketError::Service(message),
+  }
+}
+
+fn is_not_found(error: &SftpError) -> bool {
+  matches!(error, SftpError::Status(status) if status.status_code == StatusCode::NoSuchFile)
+}
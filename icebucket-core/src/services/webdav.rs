@@ -0,0 +1,442 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use reqwest::{Body, Client, Method, RequestBuilder, Response, StatusCode, Url};
+use crate::error::{IceBucketError, Result};
+use crate::logger::{LogEntry, Log};
+use crate::{SyncSettings, VERBOSE};
+use super::http::{check, child_text, header};
+use super::{RemoteObject, StorageBackend, UploadedObject, folder_of, modified_from};
+
+/// Size of each chunk in a Nextcloud chunked upload. Every chunk but the last must be
+/// at least 5MB.
+const CHUNK_SIZE: usize = 10 * 1024 * 1024; // 10MB
+
+/// Characters left as they are in paths; everything else is percent-encoded.
+const PATH_SAFE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:"><d:prop><d:getetag/><d:getcontentlength/><d:getlastmodified/><d:resourcetype/></d:prop></d:propfind>"#;
+
+/// Syncs to a folder on a WebDAV server such as Nextcloud or ownCloud. `endpoint` is
+/// the server's WebDAV URL, e.g. `https://cloud.example.com/remote.php/dav/files/alice`,
+/// and `bucket` an optional folder below it. `access_key` and `secret_key` are the
+/// user name and password (an app password, for Nextcloud); with no `access_key`,
+/// `secret_key` is sent as a bearer token instead.
+///
+/// WebDAV has no user metadata, so the server's ETag is what tells versions apart.
+/// Nextcloud and ownCloud also keep the modification time an upload asks for.
+pub struct WebDavBackend {
+  http: Client,
+  /// URL of the synced folder, without a trailing slash.
+  base_url: String,
+  /// Path of `base_url`, decoded, which listed paths are made relative to.
+  base_path: String,
+  /// Where Nextcloud takes chunked uploads, if the server looks like Nextcloud.
+  uploads_url: Option<String>,
+  auth: Auth,
+}
+
+enum Auth {
+  Basic(String, String),
+  Bearer(String),
+  None,
+}
+
+impl WebDavBackend {
+  /// Fails if `endpoint` isn't a usable URL.
+  pub fn new(sync_settings: &SyncSettings) -> Result<Self> {
+      let mut base_url = sync_settings.endpoint.trim_end_matches('/').to_string();
+      for folder in sync_settings.bucket.split('/').filter(|folder| !folder.is_empty()) {
+          base_url = format!("{}/{}", base_url, encode(folder));
+      }
+      let base_path = match Url::parse(&base_url) {
+          Ok(url) => percent_decode_str(url.path()).decode_utf8_lossy().to_string(),
+          Err(e) => return Err(IceBucketError::Config(format!("Invalid WebDAV endpoint {:?}: {}", sync_settings.endpoint, e))),
+      };
+
+      // Nextcloud's files live under .../dav/files/USER and its uploads under .../dav/uploads/USER
+      let uploads_url = sync_settings.endpoint.split_once("/remote.php/dav/files/").map(|(server, rest)| {
+          let user = rest.split('/').next().unwrap_or_default();
+          format!("{}/remote.php/dav/uploads/{}", server, user)
+      });
+
+      let auth = match (sync_settings.access_key.as_str(), sync_settings.secret_key.as_str()) {
+          ("", "") => Auth::None,
+          ("", token) => Auth::Bearer(token.to_string()),
+          (user, password) => Auth::Basic(user.to_string(), password.to_string()),
+      };
+
+      Ok(WebDavBackend {
+          http: Client::new(),
+          base_url,
+          base_path: base_path.trim_end_matches('/').to_string(),
+          uploads_url,
+          auth,
+      })
+  }
+
+  fn url_for(&self, key: &str) -> String {
+      format!("{}/{}", self.base_url, key.split('/').map(encode).collect::<Vec<_>>().join("/"))
+  }
+
+  fn request(&self, method: &str, url: &str) -> RequestBuilder {
+      let request = self.http.request(Method::from_bytes(method.as_bytes()).expect("Valid method"), url);
+      match &self.auth {
+          Auth::Basic(user, password) => request.basic_auth(user, Some(password)),
+          Auth::Bearer(token) => request.bearer_auth(token),
+          Auth::None => request,
+      }
+  }
+
+  async fn send(&self, request: RequestBuilder) -> Result<Response> {
+      Ok(request.send().await?)
+  }
+
+  /// Creates the folders leading up to `key`, like `mkdir -p`.
+  async fn create_parents(&self, key: &str) -> Result<()> {
+      let Some((folders, _)) = key.rsplit_once('/') else { return Ok(()) };
+      let mut path = String::new();
+      for folder in folders.split('/') {
+          path = if path.is_empty() { folder.to_string() } else { format!("{}/{}", path, folder) };
+          let response = self.send(self.request("MKCOL", &self.url_for(&path))).await?;
+          // 405 means the folder already exists
+          if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+              check(response, &format!("Creating folder {}", path)).await?;
+          }
+      }
+      Ok(())
+  }
+
+  /// Fetches the properties of `url` and, with `depth` 1, of everything directly in it.
+  async fn propfind(&self, url: &str, depth: &str) -> Result<Option<String>> {
+      let request = self.request("PROPFIND", url)
+          .header("Depth", depth)
+          .header("Content-Type", "application/xml; charset=utf-8")
+          .body(PROPFIND_BODY);
+      let response = self.send(request).await?;
+      if response.status() == StatusCode::NOT_FOUND {
+          return Ok(None);
+      }
+      let response = check(response, &format!("Listing {}", url)).await?;
+      Ok(Some(response.text().await?))
+  }
+
+  /// Turns a PROPFIND response into (key, is folder, object) entries, keyed by their
+  /// path below the synced folder.
+  fn parse_listing(&self, body: &str) -> Result<Vec<(String, bool, RemoteObject)>> {
+      let listing = roxmltree::Document::parse(body)
+          .map_err(|e| IceBucketError::Service(format!("Unreadable folder listing: {}", e)))?;
+      let mut entries = Vec::new();
+      for response in listing.descendants().filter(|node| node.has_tag_name("response")) {
+          let Some(href) = child_text(response, "href") else { continue };
+          // Servers send either a path or a full URL
+          let path = match Url::parse(href) {
+              Ok(url) => url.path().to_string(),
+              Err(_) => href.to_string(),
+          };
+          let path = percent_decode_str(&path).decode_utf8_lossy().to_string();
+          // Only the synced folder and what's inside it, not siblings sharing its name
+          let Some(key) = path.strip_prefix(&self.base_path) else { continue };
+          if !key.is_empty() && !key.starts_with('/') {
+              continue;
+          }
+          let key = key.trim_matches('/').to_string();
+
+          let property = |name: &str| response.descendants().find(|node| node.has_tag_name(name));
+          let is_folder = property("collection").is_some();
+          let text = |name: &str| property(name).and_then(|node| node.text()).map(str::to_string);
+          entries.push((key.clone(), is_folder, RemoteObject {
+              key,
+              size: text("getcontentlength").and_then(|size| size.parse().ok()).unwrap_or(0),
+              e_tag: text("getetag"),
+              version_id: None,
+              last_modified: text("getlastmodified").and_then(|date| httpdate::parse_http_date(&date).ok()),
+              metadata: HashMap::new(),
+          }));
+      }
+      Ok(entries)
+  }
+
+  /// Asks the server to give a file a modification time. Nextcloud and ownCloud
+  /// allow it; servers that don't are left alone.
+  async fn set_modified(&self, key: &str, modified: SystemTime) {
+      let body = format!(
+          r#"<?xml version="1.0" encoding="utf-8"?><d:propertyupdate xmlns:d="DAV:"><d:set><d:prop><d:lastmodified>{}</d:lastmodified></d:prop></d:set></d:propertyupdate>"#,
+          unix_seconds(modified),
+      );
+      let request = self.request("PROPPATCH", &self.url_for(key))
+          .header("Content-Type", "application/xml; charset=utf-8")
+          .body(body);
+      let _ = self.send(request).await;
+  }
+
+  /// Uploads the file in chunks the Nextcloud way: chunks go into an upload folder,
+  /// then moving its `.file` onto the destination assembles them. Returns `None`
+  /// without uploading anything if the server doesn't take chunked uploads.
+  async fn put_chunked(&self, key: &str, file_path: &str, modified: Option<SystemTime>, log: &Log) -> Result<Option<Response>> {
+      let Some(uploads_url) = &self.uploads_url else { return Ok(None) };
+      let destination = self.url_for(key);
+      let upload_id = format!("icebucket-{}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+      let upload_url = format!("{}/{}", uploads_url, upload_id);
+      let response = self.send(self.request("MKCOL", &upload_url).header("Destination", &destination)).await?;
+      if !response.status().is_success() {
+          return Ok(None);
+      }
+
+      let file_error = |e| IceBucketError::io(file_path, e);
+      let mut file = fs::File::open(file_path).map_err(file_error)?;
+      let total_bytes = file.metadata().map_err(file_error)?.len();
+      let num_chunks = total_bytes.div_ceil(CHUNK_SIZE as u64);
+      if VERBOSE.load(Ordering::Relaxed) {
+          println!("Uploading {} in {} chunks of {} bytes", key, num_chunks, CHUNK_SIZE);
+      }
+      for chunk_number in 1..=num_chunks {
+          let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+          (&mut file).take(CHUNK_SIZE as u64).read_to_end(&mut buffer).map_err(file_error)?;
+          let bytes_read = buffer.len() as u64;
+          let request = self.request("PUT", &format!("{}/{:05}", upload_url, chunk_number))
+              .header("Destination", &destination)
+              .header("OC-Total-Length", total_bytes)
+              .body(buffer);
+          check(self.send(request).await?, &format!("Uploading chunk {} of {}", chunk_number, key)).await?;
+
+          log.add_progress(file_path, bytes_read);
+      }
+
+      let mut request = self.request("MOVE", &format!("{}/.file", upload_url))
+          .header("Destination", &destination)
+          .header("Overwrite", "T")
+          .header("OC-Total-Length", total_bytes);
+      if let Some(modified) = modified {
+          request = request.header("X-OC-Mtime", unix_seconds(modified));
+      }
+      Ok(Some(check(self.send(request).await?, &format!("Assembling chunks of {}", key)).await?))
+  }
+
+  /// Sends the whole file in one streamed request.
+  async fn put_streamed(&self, key: &str, file_path: &str, total_bytes: u64, metadata: &HashMap<String, String>) -> Result<Response> {
+      let file = tokio::fs::File::open(file_path).await.map_err(|e| IceBucketError::io(file_path, e))?;
+      let mut request = self.request("PUT", &self.url_for(key))
+          .header("Content-Length", total_bytes)
+          .body(Body::from(file));
+      if let Some(modified) = modified_from(metadata) {
+          request = request.header("X-OC-Mtime", unix_seconds(modified));
+      }
+      check(self.send(request).await?, &format!("Uploading {}", key)).await
+  }
+
+  /// The ETag of a file just written: from the response if the server sent one,
+  /// otherwise by asking for it.
+  async fn written_e_tag(&self, key: &str, response: &Response) -> Result<Option<String>> {
+      match header(response, "oc-etag").or_else(|| header(response, "etag")) {
+          Some(e_tag) => Ok(Some(e_tag)),
+          None => Ok(self.stat(key).await?.and_then(|written| written.e_tag)),
+      }
+  }
+}
+
+#[async_trait]
+impl StorageBackend for WebDavBackend {
+  fn name(&self) -> &'static str {
+      "WebDAV"
+  }
+
+  /// Walks the folder the prefix points into one level at a time, since many
+  /// servers refuse `Depth: infinity`.
+  async fn list(&self, prefix: &str) -> Result<Vec<RemoteObject>> {
+      let mut objects = Vec::new();
+      let mut pending = vec![folder_of(prefix).to_string()];
+      while let Some(folder) = pending.pop() {
+          let url = if folder.is_empty() { format!("{}/", self.base_url) } else { format!("{}/", self.url_for(&folder)) };
+          let Some(body) = self.propfind(&url, "1").await? else { continue };
+          for (key, is_folder, object) in self.parse_listing(&body)? {
+              if key == folder {
+                  continue;
+              }
+              if is_folder {
+                  pending.push(key);
+              } else {
+                  objects.push(object);
+              }
+          }
+      }
+      // Missing folders list as empty, but a missing base folder must not look like
+      // every file was deleted
+      if objects.is_empty() && self.propfind(&format!("{}/", self.base_url), "0").await?.is_none() {
+          return Err(IceBucketError::NotFound(format!("Folder {}", self.base_url)));
+      }
+      objects.retain(|object| object.key.starts_with(prefix));
+      Ok(objects)
+  }
+
+  async fn stat(&self, key: &str) -> Result<Option<RemoteObject>> {
+      let Some(body) = self.propfind(&self.url_for(key), "0").await? else { return Ok(None) };
+      Ok(self.parse_listing(&body)?
+          .into_iter()
+          .find(|(_, is_folder, _)| !is_folder)
+          .map(|(_, _, object)| object))
+  }
+
+  async fn put(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let total_bytes = fs::metadata(file_path).map(|metadata| metadata.len()).unwrap_or(0);
+      log.add_entry(LogEntry::new(file_path.to_string(), self.base_url.clone(), key.to_string(), total_bytes));
+      self.create_parents(key).await?;
+      let response = self.put_streamed(key, file_path, total_bytes, metadata).await?;
+
+      log.update_entry(file_path, total_bytes);
+      Ok(UploadedObject {
+          e_tag: self.written_e_tag(key, &response).await?,
+          version_id: None,
+      })
+  }
+
+  /// Uses Nextcloud's chunked upload where the server has it, and a single streamed
+  /// request otherwise.
+  async fn put_multipart(&self, key: &str, file_path: &str, metadata: &HashMap<String, String>, log: &Log) -> Result<UploadedObject> {
+      let total_bytes = fs::metadata(file_path).map(|metadata| metadata.len()).unwrap_or(0);
+      log.add_entry(LogEntry::new(file_path.to_string(), self.base_url.clone(), key.to_string(), total_bytes));
+      self.create_parents(key).await?;
+
+      let response = match self.put_chunked(key, file_path, modified_from(metadata), log).await? {
+          Some(response) => response,
+          None => self.put_streamed(key, file_path, total_bytes, metadata).await?,
+      };
+
+      log.update_entry(file_path, total_bytes);
+      Ok(UploadedObject {
+          e_tag: self.written_e_tag(key, &response).await?,
+          version_id: None,
+      })
+  }
+
+  async fn get(&self, key: &str, destination: &Path, temp_path: &Path, log: &Log) -> Result<RemoteObject> {
+      let remote = self.stat(key).await?
+          .ok_or_else(|| IceBucketError::NotFound(format!("Downloading {}", key)))?;
+      let file_path = destination.to_string_lossy().to_string();
+      log.add_entry(LogEntry::new(file_path.clone(), self.base_url.clone(), key.to_string(), remote.size));
+
+      if let Some(parent) = destination.parent() {
+          fs::create_dir_all(parent).map_err(|e| IceBucketError::io(parent.display(), e))?;
+      }
+      let mut response = check(self.send(self.request("GET", &self.url_for(key))).await?, &format!("Downloading {}", key)).await?;
+      let temp_error = |e| IceBucketError::io(temp_path.display(), e);
+      let mut file = fs::File::create(temp_path).map_err(temp_error)?;
+      while let Some(chunk) = response.chunk().await? {
+          file.write_all(&chunk).map_err(temp_error)?;
+          log.add_progress(&file_path, chunk.len() as u64);
+      }
+      file.sync_all().map_err(temp_error)?;
+      drop(file);
+      fs::rename(temp_path, destination).map_err(|e| IceBucketError::io(destination.display(), e))?;
+
+      log.update_entry(&file_path, remote.size);
+      Ok(remote)
+  }
+
+  async fn delete(&self, key: &str) -> Result<()> {
+      let response = self.send(self.request("DELETE", &self.url_for(key))).await?;
+      if response.status() != StatusCode::NOT_FOUND {
+          check(response, &format!("Deleting {}", key)).await?;
+      }
+      Ok(())
+  }
+
+  /// Copies on the server. The copy is then given the current time as its
+  /// modification time, which the trash relies on, where the server allows it.
+  async fn copy(&self, from: &str, to: &str) -> Result<()> {
+      self.create_parents(to).await?;
+      let request = self.request("COPY", &self.url_for(from))
+          .header("Destination", self.url_for(to))
+          .header("Overwrite", "T");
+      check(self.send(request).await?, &format!("Copying {} to {}", from, to)).await?;
+      self.set_modified(to, SystemTime::now()).await;
+      Ok(())
+  }
+
+  async fn set_metadata(&self, key: &str, metadata: &HashMap<String, String>) -> Result<()> {
+      if let Some(modified) = modified_from(metadata) {
+          self.set_modified(key, modified).await;
+      }
+      Ok(())
+  }
+}
+
+fn encode(value: &str) -> String {
+  utf8_percent_encode(value, PATH_SAFE).to_string()
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::sync_settings;
+
+  const LISTING: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/Sync/</d:href>
+    <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/Sync/Photos/</d:href>
+    <d:propstat><d:prop><d:getetag>"folder"</d:getetag><d:resourcetype><d:collection/></d:resourcetype></d:prop></d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>https://cloud.example.com/remote.php/dav/files/alice/Sync/Photos/Gr%c3%bc%c3%9fe%20aus%20Berlin.jpg</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:getetag>"abc123"</d:getetag>
+        <d:getcontentlength>2048</d:getcontentlength>
+        <d:getlastmodified>Mon, 01 Jan 2024 00:00:00 GMT</d:getlastmodified>
+        <d:resourcetype/>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/alice/Synced/other.txt</d:href>
+    <d:propstat><d:prop><d:getcontentlength>1</d:getcontentlength><d:resourcetype/></d:prop></d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+  fn backend() -> WebDavBackend {
+      let mut settings = sync_settings("webdav", "Sync");
+      settings.endpoint = "https://cloud.example.com/remote.php/dav/files/alice/".to_string();
+      WebDavBackend::new(&settings).unwrap()
+  }
+
+  #[test]
+  fn new_finds_the_nextcloud_uploads_folder() {
+      let backend = backend();
+      assert_eq!(backend.base_url, "https://cloud.example.com/remote.php/dav/files/alice/Sync");
+      assert_eq!(backend.base_path, "/remote.php/dav/files/alice/Sync");
+      assert_eq!(backend.uploads_url.as_deref(), Some("https://cloud.example.com/remote.php/dav/uploads/alice"));
+  }
+
+  #[test]
+  fn parse_listing_keys_entries_below_the_synced_folder() {
+      let entries = backend().parse_listing(LISTING).unwrap();
+      let summary: Vec<_> = entries.iter().map(|(key, is_folder, _)| (key.as_str(), *is_folder)).collect();
+      assert_eq!(summary, vec![("", true), ("Photos", true), ("Photos/Grüße aus Berlin.jpg", false)]);
+
+      let (_, _, file) = &entries[2];
+      assert_eq!(file.key, "Photos/Grüße aus Berlin.jpg");
+      assert_eq!(file.size, 2048);
+      assert_eq!(file.e_tag.as_deref(), Some("\"abc123\""));
+      assert_eq!(file.last_modified, httpdate::parse_http_date("Mon, 01 Jan 2024 00:00:00 GMT").ok());
+  }
+
+  #[test]
+  fn parse_listing_rejects_what_isnt_xml() {
+      assert!(matches!(backend().parse_listing("<html>Login</html"), Err(IceBucketError::Service(_))));
+  }
+}
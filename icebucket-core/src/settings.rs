@@ -1,10 +1,15 @@
 use serde_json::json;
 use std::fs;
+use std::io;
+use crate::error::{IceBucketError, Result};
 use crate::Settings;
 use crate::SyncSettings;
 
+pub const SETTINGS_FILE: &str = "settings.json";
+pub const SYNC_SETTINGS_FILE: &str = "sync.json";
+
 pub fn load_or_create_settings() -> Settings {
-  let settings_path = "settings.json";
+  let settings_path = SETTINGS_FILE;
   if let Ok(settings_data) = fs::read_to_string(settings_path) {
       serde_json::from_str(&settings_data).unwrap_or_else(|_| create_default_settings(settings_path))
   } else {
@@ -12,17 +17,81 @@ pub fn load_or_create_settings() -> Settings {
   }
 }
 
+/// Fails if `sync.json` can't be read, or written when it has to be created, e.g.
+/// because the folder is gone or read-only.
+pub fn load_or_create_sync_settings(dir: &str) -> Result<SyncSettings> {
+  let sync_settings_path = format!("{}/{}", dir, SYNC_SETTINGS_FILE);
+  match fs::read_to_string(&sync_settings_path) {
+      Ok(settings_data) => match serde_json::from_str(&settings_data) {
+          Ok(settings) => Ok(settings),
+          Err(_) => create_default_sync_settings(&sync_settings_path),
+      },
+      Err(e) if e.kind() == io::ErrorKind::NotFound => create_default_sync_settings(&sync_settings_path),
+      Err(e) => Err(IceBucketError::io(&sync_settings_path, e)),
+  }
+}
+
 pub fn create_default_settings(settings_path: &str) -> Settings {
   let default_settings = Settings {
       directories_to_scan: vec!["./".to_string()],
       seconds_between_scans: 60,
+      state_directory: default_state_directory(),
+      ignore_patterns: Vec::new(),
+      temp_file_patterns: default_temp_file_patterns(),
+      watch_for_changes: default_watch_for_changes(),
+      debounce_milliseconds: default_debounce_milliseconds(),
+      max_concurrent_transfers: default_max_concurrent_transfers(),
   };
   let settings_json = json!(default_settings);
   fs::write(settings_path, settings_json.to_string()).expect("Failed to write default settings");
   default_settings
 }
 
-pub fn create_default_sync_settings(sync_settings_path: &str) -> SyncSettings {
+pub fn default_state_directory() -> String {
+  "state".to_string()
+}
+
+pub fn default_watch_for_changes() -> bool {
+  true
+}
+
+pub fn default_debounce_milliseconds() -> u64 {
+  1000
+}
+
+pub fn default_max_concurrent_transfers() -> usize {
+  8
+}
+
+pub fn default_folder_concurrent_transfers() -> usize {
+  4
+}
+
+pub fn default_part_concurrency() -> usize {
+  4
+}
+
+pub fn default_temp_file_patterns() -> Vec<String> {
+  ["~$*", "*.tmp", "*.part", "*.crdownload"].iter().map(|pattern| pattern.to_string()).collect()
+}
+
+pub fn default_seconds_until_stable() -> u64 {
+  5
+}
+
+pub fn default_max_depth() -> usize {
+  64
+}
+
+pub fn default_seconds_between_rehashes() -> u64 {
+  24 * 60 * 60
+}
+
+pub fn default_trash_retention_days() -> u64 {
+  30
+}
+
+pub fn create_default_sync_settings(sync_settings_path: &str) -> Result<SyncSettings> {
   let default_sync_settings = SyncSettings {
       service: "s3".to_string(),
       access_key: "YOUR_ACCESS_KEY".to_string(),
@@ -33,8 +102,25 @@ pub fn create_default_sync_settings(sync_settings_path: &str) -> SyncSettings {
       sync_type: "upload-only".to_string(),
       conflicts: "keep-local".to_string(),
       public: false,
+      prefix: "".to_string(),
+      deletion_policy: "".to_string(),
+      trash_retention_days: default_trash_retention_days(),
+      max_depth: default_max_depth(),
+      seconds_between_rehashes: default_seconds_between_rehashes(),
+      use_gitignore: false,
+      seconds_until_stable: default_seconds_until_stable(),
+      port: 0,
+      private_key: "".to_string(),
+      known_hosts: "".to_string(),
+      tls: "".to_string(),
+      sas_token: "".to_string(),
+      access_tier: "".to_string(),
+      credentials_file: "".to_string(),
+      force_path_style: false,
+      max_concurrent_transfers: default_folder_concurrent_transfers(),
+      part_concurrency: default_part_concurrency(),
   };
   let sync_settings_json = json!(default_sync_settings);
-  fs::write(sync_settings_path, sync_settings_json.to_string()).expect("Failed to write sync settings");
-  default_sync_settings
+  fs::write(sync_settings_path, sync_settings_json.to_string()).map_err(|e| IceBucketError::io(sync_settings_path, e))?;
+  Ok(default_sync_settings)
 }
\ No newline at end of file
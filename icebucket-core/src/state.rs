@@ -0,0 +1,224 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Sync state is kept per synced directory in `<state_directory>/<sha256 of dir>.json`.
+// It records what IceBucket last saw and synced for every file, so a restart doesn't
+// treat every file as new. Saves go through a temp file and a rename, so a crash
+// mid-write leaves the previous state intact.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct FileState {
+    pub size: u64,
+    /// Local modification time, in nanoseconds since the Unix epoch.
+    pub modified: u64,
+    pub hash: Option<String>,
+    /// Seconds since the Unix epoch when `hash` was last computed from the file.
+    pub hashed_at: u64,
+    pub e_tag: Option<String>,
+    pub version_id: Option<String>,
+    /// Seconds since the Unix epoch of the last successful sync of this file.
+    pub last_synced: u64,
+}
+
+/// A new or changed file that may still be being written: how it looked when first
+/// seen that way, and since when.
+#[derive(Debug, Clone, Copy)]
+pub struct Settling {
+    pub size: u64,
+    /// Modification time, in nanoseconds since the Unix epoch.
+    pub modified: u64,
+    pub since: Instant,
+}
+
+/// A file that failed to sync, and when to try it again.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RetryState {
+    /// Failed attempts so far.
+    pub attempts: u32,
+    /// Seconds since the Unix epoch when the next attempt is due.
+    pub next_attempt: u64,
+    pub error: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SyncState {
+    pub directory: String,
+    /// Keyed by the file's object key relative to the synced directory.
+    pub files: HashMap<String, FileState>,
+    /// Files left alone because they were still being written, and when to look at
+    /// them again. Only kept in memory; a restart rescans everything anyway.
+    #[serde(skip)]
+    pub deferred: HashMap<String, Instant>,
+    /// The deferred files that were new or changed, so the next look can tell whether
+    /// they've stayed the same since.
+    #[serde(skip)]
+    pub settling: HashMap<String, Settling>,
+    /// Files and folders the last scan couldn't read, e.g. for lack of permission.
+    /// What's in them is unknown rather than deleted. The empty key stands for the
+    /// whole folder.
+    #[serde(skip)]
+    pub unreadable: Vec<String>,
+    /// Files that failed to sync, keyed like `files`, which are retried when due
+    /// instead of waiting for the next full scan. The empty key stands for the whole
+    /// folder, when a sync failed before getting to individual files.
+    #[serde(default)]
+    pub retries: HashMap<String, RetryState>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl SyncState {
+    pub fn load(state_directory: &str, directory: &str) -> Self {
+        let path = state_file_path(state_directory, directory);
+        let mut state = match fs::read_to_string(&path) {
+            Ok(data) => match serde_json::from_str::<SyncState>(&data) {
+                Ok(state) => state,
+                Err(e) => {
+                    eprintln!("Failed to parse sync state {}: {}", path.display(), e);
+                    SyncState::default()
+                }
+            },
+            Err(_) => SyncState::default(),
+        };
+        state.directory = directory.to_string();
+        state.path = path;
+        state
+    }
+
+    /// When the earliest retry is due.
+    pub fn next_retry(&self) -> Option<Instant> {
+        let now = now_secs();
+        self.retries.values()
+            .map(|retry| retry.next_attempt)
+            .min()
+            .map(|next_attempt| Instant::now() + Duration::from_secs(next_attempt.saturating_sub(now)))
+    }
+
+    /// The keys whose retries are due by `now`, in seconds since the Unix epoch.
+    pub fn due_retries(&self, now: u64) -> Vec<String> {
+        self.retries.iter()
+            .filter(|(_, retry)| retry.next_attempt <= now)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Drops retries that were due by `now` and weren't rescheduled by the sync that
+    /// followed, because the file no longer needed syncing or the whole folder will be
+    /// retried instead.
+    pub fn forget_due_retries(&mut self, now: u64) {
+        self.retries.retain(|_, retry| retry.next_attempt > now);
+    }
+
+    /// Whether `key` is, or is in, something the last scan couldn't read.
+    pub fn is_unreadable(&self, key: &str) -> bool {
+        self.unreadable.iter().any(|unreadable| {
+            unreadable.is_empty()
+                || key == unreadable
+                || key.strip_prefix(unreadable.as_str()).is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+
+    /// The file this state is saved to.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_vec(self).map_err(io::Error::other)?;
+        let temp_path = self.path.with_extension("json.tmp");
+        {
+            let mut file = fs::File::create(&temp_path)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+fn state_file_path(state_directory: &str, directory: &str) -> PathBuf {
+    let name = format!("{:x}", Sha256::digest(directory.as_bytes()));
+    Path::new(state_directory).join(format!("{}.json", name))
+}
+
+pub fn system_time_to_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn saved_state_loads_back() {
+        let state_directory = temp_dir("state-round-trip").to_string_lossy().to_string();
+        let mut state = SyncState::load(&state_directory, "C:/Users/Me/Documents");
+        assert!(state.files.is_empty());
+
+        let file_state = FileState {
+            size: 5,
+            modified: 1_700_000_000_000_000_000,
+            hash: Some("hash".to_string()),
+            hashed_at: 1_700_000_000,
+            e_tag: Some("etag".to_string()),
+            version_id: None,
+            last_synced: 1_700_000_001,
+        };
+        state.files.insert("photos/a.jpg".to_string(), file_state.clone());
+        state.retries.insert("b.txt".to_string(), RetryState { attempts: 2, next_attempt: 1_700_000_100, error: "timed out".to_string() });
+        state.deferred.insert("c.txt".to_string(), Instant::now());
+        state.unreadable.push("private".to_string());
+        state.save().unwrap();
+        assert!(!state.path().with_extension("json.tmp").exists());
+
+        let loaded = SyncState::load(&state_directory, "C:/Users/Me/Documents");
+        assert_eq!(loaded.directory, "C:/Users/Me/Documents");
+        assert_eq!(loaded.files.get("photos/a.jpg"), Some(&file_state));
+        assert_eq!(loaded.retries, state.retries);
+        // What only holds for the running process isn't saved
+        assert!(loaded.deferred.is_empty());
+        assert!(loaded.unreadable.is_empty());
+
+        let other = SyncState::load(&state_directory, "D:/Backup");
+        assert_ne!(other.path(), state.path());
+        assert!(other.files.is_empty());
+    }
+
+    #[test]
+    fn unparsable_state_starts_over() {
+        let state_directory = temp_dir("state-corrupt").to_string_lossy().to_string();
+        let state = SyncState::load(&state_directory, "D:/Backup");
+        fs::write(state.path(), "{\"files\": ").unwrap();
+        assert!(SyncState::load(&state_directory, "D:/Backup").files.is_empty());
+    }
+
+    #[test]
+    fn retries_come_due_in_order() {
+        let mut state = SyncState::default();
+        state.retries.insert("a.txt".to_string(), RetryState { attempts: 1, next_attempt: 100, error: String::new() });
+        state.retries.insert("b.txt".to_string(), RetryState { attempts: 1, next_attempt: 200, error: String::new() });
+
+        assert_eq!(state.due_retries(99), Vec::<String>::new());
+        assert_eq!(state.due_retries(150), vec!["a.txt".to_string()]);
+        assert!(state.next_retry().is_some_and(|next| next <= Instant::now()));
+
+        state.forget_due_retries(150);
+        assert_eq!(state.retries.keys().collect::<Vec<_>>(), vec!["b.txt"]);
+        state.forget_due_retries(200);
+        assert!(state.next_retry().is_none());
+    }
+}
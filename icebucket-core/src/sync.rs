@@ -1,114 +1,1541 @@
-use std::collections::HashMap;
-use std::time::SystemTime;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
-use crate::services::s3::{service_s3_check, service_s3_upload, service_s3_multipart_upload};
-use crate::{Log, SyncSettings, VERBOSE};
-use crate::settings::create_default_sync_settings;
-use aws_sdk_s3::{Client, config::Region};
-use aws_config::BehaviorVersion;
-use aws_sdk_s3::config::Credentials;
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::types::ObjectCannedAcl;
-
-pub async fn sync_directory(dir: &str, file_map: &mut HashMap<String, SystemTime>, log: &mut Log) {
-  let sync_settings_path = format!("{}/sync.json", dir);
-  let sync_settings: SyncSettings = match fs::read_to_string(&sync_settings_path) {
-      Ok(settings_data) => match serde_json::from_str(&settings_data) {
-          Ok(settings) => settings,
-          Err(_) => create_default_sync_settings(&sync_settings_path),
-      },
-      Err(_) => create_default_sync_settings(&sync_settings_path),
+use std::time::{Duration, Instant, SystemTime};
+use crate::engine::SyncEngine;
+use tokio::sync::Mutex;
+use crate::error::{IceBucketError, Result};
+use crate::services::{RemoteObject, StorageBackend, modified_from};
+use crate::services::retry::backoff;
+use crate::{Log, Settings, SyncSettings, VERBOSE, ask_yes_no};
+use crate::exclude::ExcludeRules;
+use crate::hash::{HASH_METADATA_KEY, cached_hash, hash_file, md5_file};
+use crate::logger::LOG_FILE;
+use crate::scheduler::{LARGE_FILE_SIZE, TransferScheduler};
+use crate::settings::{SETTINGS_FILE, SYNC_SETTINGS_FILE};
+use crate::state::{FileState, Settling, SyncState, now_secs, system_time_to_nanos};
+
+/// How often the state is flushed to disk mid-scan. Each save rewrites the whole file,
+/// so saving after every so many files would make a big first sync write a quadratic
+/// amount of data.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Past this many changed paths, a full scan is quicker than checking each one.
+const MAX_SCOPE_KEYS: usize = 1000;
+
+/// Object metadata key the local modification time (nanoseconds since the Unix epoch) is stored under.
+pub const MTIME_METADATA_KEY: &str = "mtime";
+
+/// Appended to a file's name while it is being downloaded.
+pub const TEMP_FILE_SUFFIX: &str = ".icebucket-tmp";
+
+/// Where the `"trash"` deletion policy moves deleted objects, below the configured prefix.
+const TRASH_PREFIX: &str = ".trash/";
+
+/// Scheduled retries of a failed file before it's left to the next full scan.
+const MAX_FILE_ATTEMPTS: u32 = 8;
+
+/// The wait before a failed file is first retried; it doubles with each failure after that.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// A file found in the synced directory, along with its content hash.
+struct LocalFile {
+  key: String,
+  path: String,
+  size: u64,
+  modified: u64,
+  hash: String,
+}
+
+/// How much of a synced folder a sync covers.
+pub enum SyncScope {
+  /// Rescan the whole folder and, where the sync type needs it, the whole bucket prefix.
+  Everything,
+  /// Only these files or folders (below the synced folder), as reported by the watcher.
+  Paths(Vec<PathBuf>),
+}
+
+/// Syncs one folder. Folders are synced alongside each other, sharing the engine and
+/// the scheduler's transfer slots.
+pub async fn sync_directory(dir: &str, settings: &Settings, scope: SyncScope, engine: &Mutex<SyncEngine>, scheduler: &TransferScheduler, state: &mut SyncState, log: &Log) {
+  let sync_settings = engine.lock().await.sync_settings(dir);
+  let sync_settings = match sync_settings {
+      Ok(sync_settings) => sync_settings,
+      Err(e) => {
+          eprintln!("Skipping {} this time: {}", dir, e);
+          log.record_failure(dir, "", "", e.to_string());
+          return;
+      }
   };
+  let patterns = [settings.ignore_patterns.as_slice(), settings.temp_file_patterns.as_slice()].concat();
+  let mut rules = ExcludeRules::new(Path::new(dir), &patterns, sync_settings.use_gitignore);
 
-  let mut files_to_sync = Vec::new();
-  let mut deletions = Vec::new();
+  let scope_keys = match scope {
+      SyncScope::Everything => None,
+      SyncScope::Paths(paths) => {
+          let keys = scope_keys_for(dir, &paths, &sync_settings, &mut rules, state);
+          // Nothing of interest changed, e.g. only the log or the state was written.
+          // Returning before the state is saved keeps that from triggering another sync.
+          if keys.is_empty() {
+              return;
+          }
+          // A change to the folder itself (or an event overflow) calls for a full scan,
+          // as do more changes than are worth looking at one by one
+          if keys.iter().any(|key| key.is_empty()) || keys.len() > MAX_SCOPE_KEYS {
+              None
+          } else {
+              Some(keys)
+          }
+      }
+  };
+  let mut scope_keys = scope_keys.as_deref();
+
+  // The watcher only sees local changes; remote ones wait for the next full scan.
+  // Downloads that failed are retried with a full one too, as that's all this sync
+  // type does.
+  if sync_settings.sync_type == "download-only" {
+      match scope_keys {
+          Some(keys) if keys.iter().any(|key| state.retries.contains_key(key)) => scope_keys = None,
+          Some(_) => return,
+          None => {}
+      }
+  }
+
+  let backend = match SyncEngine::backend(engine, &sync_settings).await {
+      Ok(backend) => backend,
+      Err(e) => {
+          eprintln!("Skipping {} this time: {}", dir, e);
+          log.record_failure(dir, &sync_settings.service, &sync_settings.bucket, e.to_string());
+          if e.is_transient() {
+              queue_retry(state, "", &e);
+          }
+          return;
+      }
+  };
+  let backend = backend.as_ref();
+
+  let two_way = matches!(sync_settings.sync_type.as_str(), "upload-and-download" | "sync");
+  let deletion_policy = DeletionPolicy::for_settings(&sync_settings, backend, two_way).await;
+  if deletion_policy == DeletionPolicy::Trash && scope_keys.is_none() {
+      if let Err(e) = purge_trash(&sync_settings, backend).await {
+          eprintln!("Failed to purge the trash in {}: {}", sync_settings.bucket, e);
+      }
+  }
+
+  let result = match sync_settings.sync_type.as_str() {
+      "download-only" => download_changes(dir, &sync_settings, backend, scheduler, &mut rules, state, log).await,
+      _ if two_way => sync_both_ways(dir, &sync_settings, backend, scheduler, deletion_policy, scope_keys, &mut rules, state, log).await,
+      _ => upload_changes(dir, &sync_settings, backend, scheduler, deletion_policy, scope_keys, &mut rules, state, log).await,
+  };
+  // Whatever was synced before the failure is kept; the rest is retried with the
+  // whole folder if the failure may pass, or on the next full scan if not
+  match result {
+      Ok(()) if scope_keys.is_none() => {
+          state.retries.remove("");
+      }
+      Ok(()) => {}
+      Err(e) => {
+          eprintln!("Failed to sync {}: {}", dir, e);
+          log.record_failure(dir, backend.name(), &sync_settings.bucket, e.to_string());
+          if e.is_transient() {
+              queue_retry(state, "", &e);
+          }
+      }
+  }
+  save_state(state);
+}
+
+/// Takes stock of a file once it has been synced, or has failed to. A failure down to
+/// that one file is reported and recorded in the log, and the pass moves on to the
+/// next file; anything else, such as a dropped connection or rejected credentials,
+/// would fail every file after it too, so it's handed back to end the pass. Either
+/// way, a failure that may pass puts the file in the retry queue.
+fn settle_file(result: Result<()>, key: &str, file_path: &str, remote_key: &str, backend: &dyn StorageBackend, state: &mut SyncState, log: &Log) -> Result<()> {
+  let Err(error) = result else {
+      state.retries.remove(key);
+      return Ok(());
+  };
+  // Local files can be locked for a while, e.g. by an editor or a virus scanner
+  if error.is_transient() || matches!(error, IceBucketError::Io { .. }) {
+      queue_retry(state, key, &error);
+  }
+  if !error.is_per_file() {
+      return Err(error);
+  }
+  eprintln!("Failed to sync {}: {}", file_path, error);
+  log.record_failure(file_path, backend.name(), remote_key, error.to_string());
+  Ok(())
+}
 
-  match fs::read_dir(dir) {
-      Ok(entries) => {
-          for entry in entries {
-              if let Ok(entry) = entry {
-                  let path = entry.path();
-                  if path.is_file() {
-                      let metadata = fs::metadata(&path).unwrap();
-                      let modified = metadata.modified().unwrap();
-                      let path_str = path.to_str().unwrap().to_string();
-
-                      if let Some(last_modified) = file_map.get(&path_str) {
-                          if &modified > last_modified {
-                              files_to_sync.push(path_str.clone());
-                          }
-                      } else {
-                          files_to_sync.push(path_str.clone());
-                      }
-
-                      file_map.insert(path_str, modified);
+/// Runs the transfers, up to `limit` at a time, and hands each result to `settle` as
+/// it comes in. Once `settle` fails, which ends the pass, no further transfers are
+/// started, but those under way are let finish and are settled too: dropping them
+/// would cut downloads and uploads off halfway and forget the files they had synced.
+/// The first failure is returned at the end.
+///
+/// The transfers come collected, rather than made as they're needed by a closure,
+/// as holding such a closure across an await keeps the sync's future from being
+/// `Send` (rust-lang/rust#64552), and so from running as a task of its own.
+async fn run_transfers<T: Future>(transfers: Vec<T>, limit: usize, mut settle: impl FnMut(T::Output) -> Result<()>) -> Result<()> {
+  let mut waiting = transfers.into_iter();
+  let mut in_flight = FuturesUnordered::new();
+  let mut failure = None;
+  loop {
+      while failure.is_none() && in_flight.len() < limit.max(1) {
+          let Some(transfer) = waiting.next() else { break };
+          in_flight.push(transfer);
+      }
+      let Some(result) = in_flight.next().await else { break };
+      if let Err(e) = settle(result) {
+          failure.get_or_insert(e);
+      }
+  }
+  failure.map_or(Ok(()), Err)
+}
+
+/// Schedules another attempt at a failed file, or at the whole folder for the empty
+/// key, backing off with each failure until `MAX_FILE_ATTEMPTS` is reached.
+fn queue_retry(state: &mut SyncState, key: &str, error: &IceBucketError) {
+  let retry = state.retries.entry(key.to_string()).or_default();
+  retry.attempts += 1;
+  retry.error = error.to_string();
+  if retry.attempts >= MAX_FILE_ATTEMPTS {
+      eprintln!("Giving up on retrying {:?} after {} attempts until the next full scan", key, retry.attempts);
+      state.retries.remove(key);
+      return;
+  }
+  let delay = backoff(FIRST_RETRY_DELAY, MAX_RETRY_DELAY, retry.attempts);
+  retry.next_attempt = now_secs() + delay.as_secs();
+  if VERBOSE.load(Ordering::Relaxed) {
+      println!("Retrying {:?} in {:?}", key, delay);
+  }
+}
+
+/// Turns the paths reported by the watcher into the keys worth syncing, dropping
+/// IceBucket's own files and anything excluded or beyond `max_depth`. The synced
+/// folder itself maps to the empty key.
+fn scope_keys_for(dir: &str, paths: &[PathBuf], sync_settings: &SyncSettings, rules: &mut ExcludeRules, state: &SyncState) -> Vec<String> {
+  let control_files = ControlFiles::new(state);
+  let mut keys = Vec::new();
+  for path in paths {
+      let key = relative_key(Path::new(dir), path);
+      if key.is_empty() {
+          keys.push(key);
+          continue;
+      }
+      let is_dir = path.is_dir();
+      // A file sits in the folder one level up from where its key's depth would put it
+      let depth = key.split('/').count() - if is_dir { 0 } else { 1 };
+      if depth > sync_settings.max_depth
+          || is_control_key(&key)
+          || control_files.contains(path)
+          || control_files.is_in_state_directory(path)
+          || rules.is_excluded(&key, is_dir) {
+          continue;
+      }
+      keys.push(key);
+  }
+  keys.sort();
+  keys.dedup();
+  keys
+}
+
+/// Whether `key` is one of `scope_keys` or lies below one of them. Everything is in
+/// scope when there are no scope keys.
+fn in_scope(key: &str, scope_keys: Option<&[String]>) -> bool {
+  match scope_keys {
+      None => true,
+      Some(scope_keys) => scope_keys.iter().any(|scope| {
+          key == scope || key.strip_prefix(scope.as_str()).is_some_and(|rest| rest.starts_with('/'))
+      }),
+  }
+}
+
+/// Walks the synced directory, or just the files and folders in `scope_keys`, and
+/// hashes every file, reusing the hash recorded in the state when the file's size and
+/// modification time haven't changed.
+///
+/// New or changed files may still be being written until their size and modification
+/// time have stayed the same for `seconds_until_stable`. They are left out and
+/// recorded in `state.deferred` instead. Files
+/// and folders that can't be read are left out and recorded in `state.unreadable`.
+/// Callers must leave both alone rather than treat them as deleted.
+fn scan_local_files(dir: &str, sync_settings: &SyncSettings, scope_keys: Option<&[String]>, rules: &mut ExcludeRules, state: &mut SyncState) -> Vec<LocalFile> {
+  // Whatever is in scope gets checked again now, against how it looked last time
+  state.deferred.retain(|key, _| !in_scope(key, scope_keys));
+  let mut settling = HashMap::new();
+  state.settling.retain(|key, file| {
+      if in_scope(key, scope_keys) {
+          settling.insert(key.clone(), *file);
+          return false;
+      }
+      true
+  });
+
+  let mut local_files = Vec::new();
+  let mut visited = HashSet::new();
+  let mut filter = WalkFilter {
+      root: Path::new(dir),
+      control_files: ControlFiles::new(state),
+      rules,
+      unreadable: Vec::new(),
+  };
+  match scope_keys {
+      None => collect_files(Path::new(dir), 0, sync_settings.max_depth, &mut filter, &mut visited, &mut local_files),
+      Some(scope_keys) => {
+          // Scope keys have already been filtered, so only what's below them is checked
+          for key in scope_keys {
+              let Some(path) = local_path_for_key(dir, key) else { continue };
+              match fs::metadata(&path) {
+                  Ok(metadata) if metadata.is_dir() => {
+                      let depth = key.split('/').count();
+                      collect_files(&path, depth, sync_settings.max_depth, &mut filter, &mut visited, &mut local_files);
+                  }
+                  Ok(metadata) if metadata.is_file() => local_files.push(path),
+                  Ok(_) => {}
+                  Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                  Err(e) => {
+                      eprintln!("Failed to read {}: {}", path.display(), e);
+                      filter.unreadable.push(key.clone());
                   }
               }
           }
+          // A changed file and its folder may both have been reported
+          local_files.sort();
+          local_files.dedup();
       }
-      Err(e) => eprintln!("Failed to read directory {}: {}", dir, e),
   }
+  state.unreadable = filter.unreadable;
 
-  // Check for deletions
-  let current_files: Vec<String> = file_map.keys().cloned().collect();
-  for file in current_files {
-      if !fs::metadata(&file).is_ok() {
-          deletions.push(file.clone());
-          file_map.remove(&file);
-      }
-  }
-
-  // Sync files to S3
-  if sync_settings.service == "s3" {
-      let region_provider = RegionProviderChain::default_provider().or_else(Region::new(sync_settings.region));
-      let config = aws_config::defaults(BehaviorVersion::latest())
-          .region(region_provider)
-          .credentials_provider(Credentials::new(
-              sync_settings.access_key,
-              sync_settings.secret_key,
-              None,
-              None,
-              "default",
-          ))
-          .load()
-          .await;
-      let client = Client::new(&config);
-
-      for file in &files_to_sync {
-          let relative_path = file.strip_prefix(dir).unwrap().replace("\\", "/");
-          let s3_path = relative_path.trim_start_matches('/');
-          if !service_s3_check(&client, &sync_settings.bucket, s3_path).await {
+  let mut scanned = Vec::new();
+  for path in local_files {
+      let key = relative_key(Path::new(dir), &path);
+      if is_control_key(&key) {
+          continue;
+      }
+      let Some(file_path) = path.to_str().map(str::to_string) else {
+          // Its key can't name it, so its remote copy is kept rather than synced
+          eprintln!("Skipping {}: its name isn't valid Unicode", path.display());
+          state.unreadable.push(key);
+          continue;
+      };
+      let metadata = match fs::metadata(&path).and_then(|metadata| Ok((metadata.len(), metadata.modified()?))) {
+          Ok(metadata) => metadata,
+          Err(e) => {
+              eprintln!("Failed to read {}: {}", path.display(), e);
+              state.unreadable.push(key);
+              continue;
+          }
+      };
+      let (size, modified) = metadata;
+      let modified = system_time_to_nanos(modified);
+
+      let known = state.files.get(&key);
+      let unchanged = known.is_some_and(|known| known.size == size && known.modified == modified);
+      if !unchanged {
+          let last_seen = settling.get(&key).filter(|file| file.size == size && file.modified == modified);
+          let since = last_seen.map_or_else(Instant::now, |file| file.since);
+          let stable_at = since + Duration::from_secs(sync_settings.seconds_until_stable);
+          if stable_at > Instant::now() {
               if VERBOSE.load(Ordering::Relaxed) {
-                  println!("S3 << {}", s3_path);
+                  println!("Still being written, waiting: {}", path.display());
               }
-              if fs::metadata(file).unwrap().len() > 5 * 1024 * 1024 {
-                  // Use multipart upload for files larger than 5MB
-                  service_s3_multipart_upload(&client, &sync_settings.bucket, s3_path, file, log).await;
-              } else {
-                  service_s3_upload(&client, &sync_settings.bucket, s3_path, file, log).await;
+              state.settling.insert(key.clone(), Settling { size, modified, since });
+              state.deferred.insert(key, stable_at);
+              continue;
+          }
+      }
+
+      let hash = match cached_hash(known, size, modified, sync_settings.seconds_between_rehashes) {
+          Some(hash) => hash,
+          None => match hash_file(&path) {
+              Ok(hash) => {
+                  if let Some(known) = state.files.get_mut(&key) {
+                      known.hashed_at = now_secs();
+                  }
+                  hash
               }
-              if sync_settings.public {
-                  // Set ACL to public-read
-                  client.put_object_acl()
-                      .bucket(&sync_settings.bucket)
-                      .key(s3_path)
-                      .acl(ObjectCannedAcl::PublicRead)
-                      .send()
-                      .await
-                      .expect("Failed to set ACL");
+              Err(e) => {
+                  eprintln!("Failed to hash {}: {}", path.display(), e);
+                  state.unreadable.push(key);
+                  continue;
               }
+          },
+      };
+
+      // A write that started while the file was being hashed
+      if !unchanged && file_changed(&path, size, modified) {
+          state.deferred.insert(key, Instant::now() + Duration::from_secs(sync_settings.seconds_until_stable));
+          continue;
+      }
+
+      scanned.push(LocalFile {
+          key,
+          path: file_path,
+          size,
+          modified,
+          hash,
+      });
+  }
+  scanned
+}
+
+/// Uploads every local file whose content differs from what was last synced.
+#[allow(clippy::too_many_arguments)]
+async fn upload_changes(dir: &str, sync_settings: &SyncSettings, backend: &dyn StorageBackend, scheduler: &TransferScheduler, deletion_policy: DeletionPolicy, scope_keys: Option<&[String]>, rules: &mut ExcludeRules, state: &mut SyncState, log: &Log) -> Result<()> {
+  let prefix = key_prefix(&sync_settings.prefix);
+  let mut files_to_sync = Vec::new();
+  let mut deletions = Vec::new();
+
+  let mut seen_keys = HashSet::new();
+  for local in scan_local_files(dir, sync_settings, scope_keys, rules, state) {
+      seen_keys.insert(local.key.clone());
+      match state.files.get_mut(&local.key) {
+          Some(known) if known.hash.as_deref() == Some(local.hash.as_str()) => {
+              // Touched but unchanged: remember the new size/mtime so it isn't hashed again
+              known.size = local.size;
+              known.modified = local.modified;
+          }
+          _ => files_to_sync.push(local),
+      }
+  }
+
+  // Check for deletions
+  let known_keys: Vec<String> = state.files.keys().filter(|key| in_scope(key, scope_keys)).cloned().collect();
+  for key in known_keys {
+      if !seen_keys.contains(&key) && !state.deferred.contains_key(&key) && !state.is_unreadable(&key) {
+          // Newly excluded files (and nested folders' settings synced by earlier
+          // versions) are only forgotten; their remote copies stay. Deleted ones are
+          // forgotten once the deletion has gone through.
+          if rules.is_excluded(&key, false) || is_control_key(&key) {
+              state.files.remove(&key);
+          } else {
+              deletions.push(key);
           }
       }
   }
 
+  let remote_index = RemoteIndex::new(backend, &prefix, scope_keys, files_to_sync.len()).await?;
+  // What each changed file's object was when last synced, for the listing to be checked against
+  let synced_e_tags: HashMap<&str, String> = files_to_sync.iter()
+      .filter_map(|local| Some((local.key.as_str(), state.files.get(&local.key)?.e_tag.clone()?)))
+      .collect();
+  let (prefix, remote_index, synced_e_tags) = (&prefix, &remote_index, &synced_e_tags);
+  let uploads = files_to_sync.iter().map(|local| async move {
+      let remote_key = format!("{}{}", prefix, local.key);
+      let synced_e_tag = synced_e_tags.get(local.key.as_str()).map(String::as_str);
+      let result = upload_if_changed(backend, remote_index, &remote_key, local, synced_e_tag, scheduler, log).await;
+      (local, remote_key, result)
+  }).collect();
+  let mut last_save = Instant::now();
+  run_transfers(uploads, sync_settings.max_concurrent_transfers, |(local, remote_key, result)| {
+      let result = result.map(|synced| record(state, &local.key, synced, sync_settings));
+      settle_file(result, &local.key, &local.path, &remote_key, backend, state, log)?;
+
+      // Persist progress regularly so a crash doesn't lose a long scan's work
+      if last_save.elapsed() >= STATE_SAVE_INTERVAL {
+          save_state(state);
+          last_save = Instant::now();
+      }
+      Ok(())
+  }).await?;
+
   // Placeholder for syncing files
   if !files_to_sync.is_empty() {
-      let recent_action = format!("S3 << {}", files_to_sync.last().unwrap());
-      println!("Files to sync in {}: {:?}", dir, files_to_sync);
-      fs::write("sync.log", recent_action).expect("Unable to write to sync.log");
+      let recent_action = format!("{} << {}", backend.name(), files_to_sync.last().unwrap().path);
+      let synced_paths: Vec<&String> = files_to_sync.iter().map(|local| &local.path).collect();
+      println!("Files to sync in {}: {:?}", dir, synced_paths);
+      if let Err(e) = fs::write(LOG_FILE, recent_action) {
+          eprintln!("Unable to write to {}: {}", LOG_FILE, e);
+      }
   }
   if !deletions.is_empty() {
-      println!("Files to delete in {}: {:?}", dir, deletions);
+      println!("Files deleted in {} ({:?}): {:?}", dir, deletion_policy, deletions);
+      let removals = deletions.iter().map(|key| async move {
+          let remote_key = format!("{}{}", prefix, key);
+          let result = delete_remote(backend, sync_settings, deletion_policy, &remote_key, scheduler).await;
+          (key, remote_key, result)
+      }).collect();
+      run_transfers(removals, sync_settings.max_concurrent_transfers, |(key, remote_key, result)| {
+          let result = result.map(|()| record(state, key, Synced::Deleted, sync_settings));
+          settle_file(result, key, &Path::new(dir).join(key).to_string_lossy(), &remote_key, backend, state, log)
+      }).await?;
+  }
+  Ok(())
+}
+
+/// Downloads every object under the configured prefix that is new or has changed
+/// since it was last synced. Local edits are overwritten only when the remote changes.
+async fn download_changes(dir: &str, sync_settings: &SyncSettings, backend: &dyn StorageBackend, scheduler: &TransferScheduler, rules: &mut ExcludeRules, state: &mut SyncState, log: &Log) -> Result<()> {
+  let prefix = key_prefix(&sync_settings.prefix);
+  let remote_objects = backend.list(&prefix).await?;
+
+  let mut candidates = Vec::new();
+  for remote in &remote_objects {
+      let Some(key) = relative_remote_key(&prefix, &remote.key) else { continue };
+      if rules.is_excluded(key, false) {
+          continue;
+      }
+      let local_path = match local_path_for_key(dir, key) {
+          Some(path) => path,
+          None => {
+              eprintln!("Skipping object with an unsafe key: {}", remote.key);
+              continue;
+          }
+      };
+
+      let known = state.files.get(key);
+      let remote_unchanged = known.is_some_and(|known| known.e_tag.is_some() && known.e_tag == remote.e_tag);
+      if remote_unchanged && local_path.is_file() {
+          continue;
+      }
+      candidates.push((key, remote, local_path));
+  }
+
+  let downloads = candidates.into_iter().map(|(key, remote, local_path)| async move {
+      let result = download_if_changed(backend, remote, &local_path, scheduler, log).await;
+      (key, remote, local_path, result)
+  }).collect();
+  let mut downloaded = Vec::new();
+  let mut last_save = Instant::now();
+  let result = run_transfers(downloads, sync_settings.max_concurrent_transfers, |(key, remote, local_path, result)| {
+      if matches!(result, Ok(Synced::Downloaded(_))) {
+          downloaded.push(local_path.to_string_lossy().to_string());
+      }
+      let result = result.map(|synced| record(state, key, synced, sync_settings));
+      settle_file(result, key, &local_path.to_string_lossy(), &remote.key, backend, state, log)?;
+
+      // Persist progress regularly so a crash doesn't lose a long scan's work
+      if last_save.elapsed() >= STATE_SAVE_INTERVAL {
+          save_state(state);
+          last_save = Instant::now();
+      }
+      Ok(())
+  }).await;
+
+  if !downloaded.is_empty() {
+      println!("Files downloaded in {}: {:?}", dir, downloaded);
+  }
+  result
+}
+
+/// Two-way sync. Each file is compared against the state recorded at its last sync:
+/// a side whose hash (local) or ETag (remote) differs from that base has changed.
+/// Changes on one side are copied to the other; changes on both sides are a conflict
+/// resolved by the `conflicts` setting.
+///
+/// When `scope_keys` is set, only those local paths changed. Their objects are looked
+/// up one by one instead of listing the whole bucket prefix.
+#[allow(clippy::too_many_arguments)]
+async fn sync_both_ways(dir: &str, sync_settings: &SyncSettings, backend: &dyn StorageBackend, scheduler: &TransferScheduler, deletion_policy: DeletionPolicy, scope_keys: Option<&[String]>, rules: &mut ExcludeRules, state: &mut SyncState, log: &Log) -> Result<()> {
+  let prefix = key_prefix(&sync_settings.prefix);
+  let local_files: HashMap<String, LocalFile> = scan_local_files(dir, sync_settings, scope_keys, rules, state)
+      .into_iter()
+      .map(|local| (local.key.clone(), local))
+      .collect();
+
+  // Excluded files are left alone on both sides
+  state.files.retain(|key, _| !rules.is_excluded(key, false) && !is_control_key(key));
+
+  // Keys whose object couldn't be looked up; without knowing what's there they can't
+  // be told apart from remote deletions, so they wait for the next pass
+  let mut unknown_keys = HashSet::new();
+  let remote_objects: HashMap<String, RemoteObject> = match scope_keys {
+      None => backend.list(&prefix).await?
+          .into_iter()
+          .filter_map(|remote| Some((relative_remote_key(&prefix, &remote.key)?.to_string(), remote)))
+          .filter(|(key, _)| !rules.is_excluded(key, false))
+          .collect(),
+      Some(_) => {
+          let candidates: HashSet<String> = local_files.keys()
+              .chain(state.files.keys().filter(|key| in_scope(key, scope_keys)))
+              .cloned()
+              .collect();
+          let mut remote_objects = HashMap::new();
+          for key in candidates {
+              let remote_key = format!("{}{}", prefix, key);
+              match backend.stat(&remote_key).await {
+                  Ok(Some(remote)) => {
+                      remote_objects.insert(key, remote);
+                  }
+                  Ok(None) => {}
+                  Err(e) => {
+                      let local_path = Path::new(dir).join(&key);
+                      settle_file(Err(e), &key, &local_path.to_string_lossy(), &remote_key, backend, state, log)?;
+                      unknown_keys.insert(key);
+                  }
+              }
+          }
+          remote_objects
+      }
+  };
+
+  // An empty listing where files were synced before looks more like the wrong bucket,
+  // or a destination that has gone missing, than everything having been deleted there
+  let remote_vanished = scope_keys.is_none() && remote_objects.is_empty() && !state.files.is_empty();
+  if remote_vanished {
+      let message = format!("{} listed no files under {:?}, although {} were synced before; local files are left alone", backend.name(), prefix, state.files.len());
+      eprintln!("{}: {}", dir, message);
+      log.record_failure(dir, backend.name(), &sync_settings.bucket, message);
+  }
+
+  let mut keys: Vec<String> = local_files.keys()
+      .chain(remote_objects.keys())
+      .chain(state.files.keys().filter(|key| in_scope(key, scope_keys)))
+      // Files still being written, or that couldn't be read, wait for a later pass
+      .filter(|key| !state.deferred.contains_key(*key) && !state.is_unreadable(key) && !unknown_keys.contains(*key))
+      .cloned()
+      .collect::<HashSet<String>>()
+      .into_iter()
+      .collect();
+  keys.sort();
+
+  let mut transfers = Vec::new();
+  for key in keys {
+      let local = local_files.get(&key);
+      let remote = remote_objects.get(&key);
+      let local_path = match local_path_for_key(dir, &key) {
+          Some(path) => path,
+          None => {
+              eprintln!("Skipping object with an unsafe key: {}{}", prefix, key);
+              continue;
+          }
+      };
+
+      let transfer = match two_way_step(local, remote, state.files.get(&key), remote_vanished) {
+          TwoWayStep::Unchanged => {
+              if let (Some(local), Some(known)) = (local, state.files.get_mut(&key)) {
+                  known.size = local.size;
+                  known.modified = local.modified;
+              }
+              continue;
+          }
+          TwoWayStep::Transfer(transfer) => transfer,
+          TwoWayStep::Forget => {
+              state.files.remove(&key);
+              continue;
+          }
+          TwoWayStep::Keep => continue,
+          TwoWayStep::DeleteLocal(local) => {
+              if VERBOSE.load(Ordering::Relaxed) {
+                  println!("Local x {}", local.path);
+              }
+              if let Err(e) = fs::remove_file(&local.path) {
+                  eprintln!("Failed to delete {}: {}", local.path, e);
+              }
+              state.files.remove(&key);
+              continue;
+          }
+      };
+      transfers.push((key, local_path, transfer));
+  }
+
+  let prefix = &prefix;
+  let transfers = transfers.into_iter().map(|(key, local_path, transfer)| async move {
+      let remote_key = format!("{}{}", prefix, key);
+      let result = match transfer {
+          Transfer::Resolve(local, remote) => resolve_conflict(sync_settings, backend, scheduler, &remote_key, local, remote, &local_path, log).await,
+          Transfer::Upload(local) => upload_file(backend, scheduler, &remote_key, local, log).await,
+          Transfer::Download(remote) => download_file(backend, scheduler, remote, &local_path, log).await,
+          Transfer::DeleteRemote => delete_remote(backend, sync_settings, deletion_policy, &remote_key, scheduler).await.map(|()| Synced::Deleted),
+      };
+      (key, local_path, remote_key, result)
+  }).collect();
+  let mut last_save = Instant::now();
+  run_transfers(transfers, sync_settings.max_concurrent_transfers, |(key, local_path, remote_key, result)| {
+      let result = result.map(|synced| record(state, &key, synced, sync_settings));
+      settle_file(result, &key, &local_path.to_string_lossy(), &remote_key, backend, state, log)?;
+
+      // Persist progress regularly so a crash doesn't lose a long scan's work
+      if last_save.elapsed() >= STATE_SAVE_INTERVAL {
+          save_state(state);
+          last_save = Instant::now();
+      }
+      Ok(())
+  }).await
+}
+
+/// What a two-way sync does with one file, given the local file, the remote object
+/// and what was last synced (`base`), any of which may be missing.
+fn two_way_step<'a>(local: Option<&'a LocalFile>, remote: Option<&'a RemoteObject>, base: Option<&FileState>, remote_vanished: bool) -> TwoWayStep<'a> {
+  let local_changed = match (local, base) {
+      (Some(local), Some(base)) => base.hash.as_deref() != Some(local.hash.as_str()),
+      (None, None) => false,
+      _ => true,
+  };
+  let remote_changed = match (remote, base) {
+      (Some(remote), Some(base)) => base.e_tag.is_none() || base.e_tag != remote.e_tag,
+      (None, None) => false,
+      _ => true,
+  };
+  match (local, remote) {
+      _ if !local_changed && !remote_changed => TwoWayStep::Unchanged,
+      (Some(local), Some(remote)) if local_changed && remote_changed => TwoWayStep::Transfer(Transfer::Resolve(local, remote)),
+      // Edited here, or edited here after being deleted remotely: the edit wins
+      (Some(local), _) if local_changed => TwoWayStep::Transfer(Transfer::Upload(local)),
+      (_, Some(remote)) if remote_changed => TwoWayStep::Transfer(Transfer::Download(remote)),
+      (None, None) => TwoWayStep::Forget,
+      // Only the deletion is new: carry it over to the other side
+      (None, Some(_)) => TwoWayStep::Transfer(Transfer::DeleteRemote),
+      (Some(_), _) if remote_vanished => TwoWayStep::Keep,
+      (Some(local), _) => TwoWayStep::DeleteLocal(local),
+  }
+}
+
+/// The outcome of `two_way_step`.
+enum TwoWayStep<'a> {
+  /// Nothing changed on either side.
+  Unchanged,
+  Transfer(Transfer<'a>),
+  /// Deleted on both sides: only the state still has it.
+  Forget,
+  /// Missing from a listing that can't be trusted, so left as it is.
+  Keep,
+  /// Deleted remotely.
+  DeleteLocal(&'a LocalFile),
+}
+
+/// What a two-way sync has to do with the bucket for one file.
+enum Transfer<'a> {
+  /// Changed on both sides.
+  Resolve(&'a LocalFile, &'a RemoteObject),
+  Upload(&'a LocalFile),
+  Download(&'a RemoteObject),
+  /// Deleted locally.
+  DeleteRemote,
+}
+
+/// What a file's sync came to, for `record` to note in the state once its transfer
+/// is done. Transfers run alongside each other, so they leave the state alone.
+enum Synced {
+  /// The file and its object already held the same content.
+  Matched(FileState),
+  /// The file was uploaded. Without a hash, it changed while being read.
+  Uploaded(FileState),
+  /// The object was downloaded over the file, which is `None` if it couldn't be
+  /// read back afterwards.
+  Downloaded(Option<FileState>),
+  /// The object was deleted, or moved to the trash, after the file was.
+  Deleted,
+}
+
+/// Notes in the state what became of a file.
+fn record(state: &mut SyncState, key: &str, synced: Synced, sync_settings: &SyncSettings) {
+  match synced {
+      Synced::Matched(file_state) | Synced::Downloaded(Some(file_state)) => {
+          state.files.insert(key.to_string(), file_state);
+      }
+      Synced::Uploaded(file_state) => {
+          // Uploaded mid-write: look at it again once it has settled
+          if file_state.hash.is_none() {
+              state.deferred.insert(key.to_string(), Instant::now() + Duration::from_secs(sync_settings.seconds_until_stable));
+          }
+          state.files.insert(key.to_string(), file_state);
+      }
+      Synced::Downloaded(None) => {}
+      Synced::Deleted => {
+          state.files.remove(key);
+      }
+  }
+}
+
+/// What happens to the remote object when a synced file is deleted locally.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DeletionPolicy {
+  /// Leave the object in the bucket.
+  Ignore,
+  /// Delete the object.
+  Delete,
+  /// Move the object under `.trash/`, where it is purged after `trash_retention_days`.
+  Trash,
+  /// Delete the object and rely on bucket versioning to keep the old versions.
+  Versioned,
+}
+
+impl DeletionPolicy {
+  /// Reads `deletion_policy` from the settings. Left unset, two-way sync deletes
+  /// remote copies (otherwise they would be downloaded again) and other modes ignore
+  /// deletions. `"versioned"` falls back to ignoring deletions if the bucket doesn't
+  /// have versioning enabled, or it can't be told whether it does, since the delete
+  /// would otherwise be permanent.
+  async fn for_settings(sync_settings: &SyncSettings, backend: &dyn StorageBackend, two_way: bool) -> Self {
+      match sync_settings.deletion_policy.as_str() {
+          "" if two_way => DeletionPolicy::Delete,
+          "" | "ignore" => DeletionPolicy::Ignore,
+          "delete" => DeletionPolicy::Delete,
+          "trash" => DeletionPolicy::Trash,
+          "versioned" => match backend.versioning_enabled().await {
+              Ok(true) => DeletionPolicy::Versioned,
+              Ok(false) => {
+                  eprintln!("Bucket {} doesn't have versioning enabled; deletions will be ignored", sync_settings.bucket);
+                  DeletionPolicy::Ignore
+              }
+              Err(e) => {
+                  eprintln!("Couldn't check versioning on bucket {} ({}); deletions will be ignored this time", sync_settings.bucket, e);
+                  DeletionPolicy::Ignore
+              }
+          },
+          other => {
+              eprintln!("Unknown deletion_policy {:?}; deletions will be ignored", other);
+              DeletionPolicy::Ignore
+          }
+      }
+  }
+}
+
+/// Applies the deletion policy to the object of a file that was deleted locally.
+async fn delete_remote(backend: &dyn StorageBackend, sync_settings: &SyncSettings, deletion_policy: DeletionPolicy, remote_key: &str, scheduler: &TransferScheduler) -> Result<()> {
+  let _slot = scheduler.slot(0).await;
+  match deletion_policy {
+      DeletionPolicy::Ignore => return Ok(()),
+      DeletionPolicy::Delete | DeletionPolicy::Versioned => {}
+      DeletionPolicy::Trash => {
+          let prefix = key_prefix(&sync_settings.prefix);
+          let relative = remote_key.strip_prefix(&prefix).unwrap_or(remote_key);
+          let trash_path = format!("{}{}{}", prefix, TRASH_PREFIX, relative);
+          match backend.copy(remote_key, &trash_path).await {
+              // Already gone from the bucket, so there's nothing left to keep
+              Err(IceBucketError::NotFound(_)) => return Ok(()),
+              result => result?,
+          }
+      }
+  }
+  if VERBOSE.load(Ordering::Relaxed) {
+      println!("{} x {}", backend.name(), remote_key);
+  }
+  backend.delete(remote_key).await
+}
+
+/// Permanently deletes trashed objects older than `trash_retention_days`. The copy
+/// into the trash resets an object's last-modified time, so that is when it was trashed.
+async fn purge_trash(sync_settings: &SyncSettings, backend: &dyn StorageBackend) -> Result<()> {
+  let trash_prefix = format!("{}{}", key_prefix(&sync_settings.prefix), TRASH_PREFIX);
+  let retention = Duration::from_secs(sync_settings.trash_retention_days * 24 * 60 * 60);
+  let cutoff = SystemTime::now() - retention;
+  for object in backend.list(&trash_prefix).await? {
+      if object.last_modified.is_some_and(|trashed_at| trashed_at < cutoff) {
+          if VERBOSE.load(Ordering::Relaxed) {
+              println!("{} x {}", backend.name(), object.key);
+          }
+          backend.delete(&object.key).await?;
+      }
+  }
+  Ok(())
+}
+
+/// Handles a file changed both locally and remotely since the last sync. If both
+/// sides ended up with the same content there is nothing to resolve.
+#[allow(clippy::too_many_arguments)]
+async fn resolve_conflict(sync_settings: &SyncSettings, backend: &dyn StorageBackend, scheduler: &TransferScheduler, remote_key: &str, local: &LocalFile, remote: &RemoteObject, local_path: &Path, log: &Log) -> Result<Synced> {
+  if let Some(head) = backend.stat(remote_key).await? {
+      if head.metadata.get(HASH_METADATA_KEY) == Some(&local.hash) {
+          return Ok(Synced::Matched(synced_state(local, &head)));
+      }
+  }
+
+  println!("Conflict on {} ({})", local.path, sync_settings.conflicts);
+  match sync_settings.conflicts.as_str() {
+      "use-remote" | "keep-remote" => {
+          download_file(backend, scheduler, remote, local_path, log).await
+      }
+      "keep-both" => {
+          // The remote copy takes the original name; the local one is kept alongside it
+          // and picked up as a new file on the next scan
+          let conflict_path = conflict_path_for(local_path);
+          fs::rename(local_path, &conflict_path).map_err(|e| IceBucketError::io(conflict_path.display(), e))?;
+          download_file(backend, scheduler, remote, local_path, log).await
+      }
+      _ => {
+          upload_file(backend, scheduler, remote_key, local, log).await
+      }
+  }
+}
+
+/// Uploads a file unless its object already holds the same content, e.g. because it
+/// was uploaded from another machine or before the state was lost.
+#[allow(clippy::too_many_arguments)]
+async fn upload_if_changed(backend: &dyn StorageBackend, remote_index: &RemoteIndex, remote_key: &str, local: &LocalFile, synced_e_tag: Option<&str>, scheduler: &TransferScheduler, log: &Log) -> Result<Synced> {
+  let remote = match remote_index.find(backend, remote_key, local, synced_e_tag).await? {
+      RemoteCopy::Stale => None,
+      RemoteCopy::Current(remote) => return Ok(Synced::Matched(synced_state(local, &remote))),
+      RemoteCopy::Found(remote) => Some(remote),
+  };
+  match remote.filter(|remote| is_remote_current(remote, local)) {
+      Some(remote) => {
+          if !remote.metadata.contains_key(HASH_METADATA_KEY) {
+              // Matched on size and mtime; tag it so other machines can compare by hash
+              let mut metadata = remote.metadata.clone();
+              metadata.insert(HASH_METADATA_KEY.to_string(), local.hash.clone());
+              backend.set_metadata(remote_key, &metadata).await?;
+          }
+          Ok(Synced::Matched(synced_state(local, &remote)))
+      }
+      None => upload_file(backend, scheduler, remote_key, local, log).await,
+  }
+}
+
+/// Uploads a local file, tagging the object with its hash and modification time.
+async fn upload_file(backend: &dyn StorageBackend, scheduler: &TransferScheduler, remote_key: &str, local: &LocalFile, log: &Log) -> Result<Synced> {
+  let _slot = scheduler.slot(local.size).await;
+  if VERBOSE.load(Ordering::Relaxed) {
+      println!("{} << {}", backend.name(), remote_key);
+  }
+  let metadata = HashMap::from([
+      (HASH_METADATA_KEY.to_string(), local.hash.clone()),
+      (MTIME_METADATA_KEY.to_string(), local.modified.to_string()),
+  ]);
+  let uploaded = if local.size > LARGE_FILE_SIZE {
+      // Use multipart upload for files larger than 5MB
+      backend.put_multipart(remote_key, &local.path, &metadata, log).await?
+  } else {
+      backend.put(remote_key, &local.path, &metadata, log).await?
+  };
+  // The upload may have caught the file mid-write. Record the object, but without a
+  // hash, so the next pass sees the file as changed and uploads it again.
+  let hash = if file_changed(Path::new(&local.path), local.size, local.modified) {
+      println!("{} changed during upload; it will be uploaded again", local.path);
+      None
+  } else {
+      Some(local.hash.clone())
+  };
+  Ok(Synced::Uploaded(FileState {
+      size: local.size,
+      modified: local.modified,
+      hash,
+      hashed_at: now_secs(),
+      e_tag: uploaded.e_tag,
+      version_id: uploaded.version_id,
+      last_synced: now_secs(),
+  }))
+}
+
+/// Downloads an object unless the local file already holds the same content, e.g.
+/// on first run against a populated folder.
+async fn download_if_changed(backend: &dyn StorageBackend, remote: &RemoteObject, local_path: &Path, scheduler: &TransferScheduler, log: &Log) -> Result<Synced> {
+  if local_path.is_file() {
+      let local_hash = hash_file(local_path).ok();
+      // Only some listings include metadata; otherwise it takes a request of its own
+      let remote_hash = match remote.metadata.get(HASH_METADATA_KEY) {
+          Some(hash) => Some(hash.clone()),
+          None => backend.stat(&remote.key).await?.and_then(|head| head.metadata.get(HASH_METADATA_KEY).cloned()),
+      };
+      if local_hash.is_some() && local_hash == remote_hash {
+          if let Some(file_state) = local_file_state(local_path, local_hash, remote) {
+              return Ok(Synced::Matched(file_state));
+          }
+      }
+  }
+  download_file(backend, scheduler, remote, local_path, log).await
+}
+
+/// Downloads an object over `local_path`.
+async fn download_file(backend: &dyn StorageBackend, scheduler: &TransferScheduler, remote: &RemoteObject, local_path: &Path, log: &Log) -> Result<Synced> {
+  let _slot = scheduler.slot(remote.size).await;
+  if VERBOSE.load(Ordering::Relaxed) {
+      println!("{} >> {}", backend.name(), remote.key);
+  }
+  let temp_path = temp_path_for(local_path);
+  let fetched = match backend.get(&remote.key, local_path, &temp_path, log).await {
+      Ok(fetched) => fetched,
+      Err(e) => {
+          // Don't leave a partial download behind
+          let _ = fs::remove_file(&temp_path);
+          return Err(e);
+      }
+  };
+  restore_modified_time(local_path, &fetched);
+
+  let hash = fetched.metadata.get(HASH_METADATA_KEY).cloned().or_else(|| hash_file(local_path).ok());
+  Ok(Synced::Downloaded(local_file_state(local_path, hash, &fetched)))
+}
+
+/// The state of a file whose object already holds the same content.
+fn synced_state(local: &LocalFile, remote: &RemoteObject) -> FileState {
+  FileState {
+      size: local.size,
+      modified: local.modified,
+      hash: Some(local.hash.clone()),
+      hashed_at: now_secs(),
+      e_tag: remote.e_tag.clone(),
+      version_id: remote.version_id.clone(),
+      last_synced: now_secs(),
+  }
+}
+
+/// Records a file that now matches `remote`, reading its size and mtime back from disk.
+fn local_file_state(path: &Path, hash: Option<String>, remote: &RemoteObject) -> Option<FileState> {
+  let metadata = fs::metadata(path).ok()?;
+  Some(FileState {
+      size: metadata.len(),
+      modified: system_time_to_nanos(metadata.modified().ok()?),
+      hash,
+      hashed_at: now_secs(),
+      e_tag: remote.e_tag.clone(),
+      version_id: remote.version_id.clone(),
+      last_synced: now_secs(),
+  })
+}
+
+/// Sets a downloaded file's modification time to the one it had where it was uploaded,
+/// falling back to the object's last-modified time.
+fn restore_modified_time(path: &Path, remote: &RemoteObject) {
+  let modified = modified_from(&remote.metadata).or(remote.last_modified);
+  if let Some(modified) = modified {
+      let result = fs::OpenOptions::new().write(true).open(path).and_then(|file| file.set_modified(modified));
+      if let Err(e) = result {
+          eprintln!("Failed to set modified time on {}: {}", path.display(), e);
+      }
+  }
+}
+
+/// Whether the file's size or modification time differ from the ones given.
+fn file_changed(path: &Path, size: u64, modified: u64) -> bool {
+  match fs::metadata(path) {
+      Ok(metadata) => metadata.len() != size || metadata.modified().map(system_time_to_nanos).ok() != Some(modified),
+      Err(_) => true,
+  }
+}
+
+/// Normalizes the configured prefix so it always ends in `/` unless empty.
+fn key_prefix(prefix: &str) -> String {
+  let prefix = prefix.trim_matches('/');
+  if prefix.is_empty() {
+      String::new()
+  } else {
+      format!("{}/", prefix)
+  }
+}
+
+/// Maps an object key back to a path below `dir`. Keys containing `..`, drive
+/// letters or other components that would escape the synced directory are rejected.
+fn local_path_for_key(dir: &str, key: &str) -> Option<PathBuf> {
+  let mut path = PathBuf::from(dir);
+  for part in key.split('/') {
+      if part.is_empty() || part == "." || part == ".." || part.contains(['\\', ':']) {
+          return None;
+      }
+      path.push(part);
+  }
+  Some(path)
+}
+
+/// Strips the configured prefix from an object key. Returns `None` for keys outside
+/// the prefix and for folder placeholders.
+fn relative_remote_key<'a>(prefix: &str, key: &'a str) -> Option<&'a str> {
+  match key.strip_prefix(prefix) {
+      Some(key) if key.starts_with(TRASH_PREFIX) || is_control_key(key) => None,
+      Some(key) if !key.is_empty() && !key.ends_with('/') => Some(key),
+      _ => None,
+  }
+}
+
+/// Picks the name a conflicting local file is moved to:
+/// `name (conflict from HOST YYYY-MM-DD).ext`, numbered if that is already taken.
+fn conflict_path_for(path: &Path) -> PathBuf {
+  let host = env::var("COMPUTERNAME")
+      .or_else(|_| env::var("HOSTNAME"))
+      .unwrap_or_else(|_| "unknown".to_string());
+  let date = format_date(now_secs());
+  let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+  let extension = path.extension().map(|extension| extension.to_string_lossy().to_string());
+
+  let mut attempt = 1;
+  loop {
+      let label = if attempt == 1 {
+          format!("conflict from {} {}", host, date)
+      } else {
+          format!("conflict from {} {} {}", host, date, attempt)
+      };
+      let name = match &extension {
+          Some(extension) => format!("{} ({}).{}", stem, label, extension),
+          None => format!("{} ({})", stem, label),
+      };
+      let candidate = path.with_file_name(name);
+      if !candidate.exists() {
+          return candidate;
+      }
+      attempt += 1;
+  }
+}
+
+/// Formats seconds since the Unix epoch as a `YYYY-MM-DD` date (UTC).
+pub fn format_date(secs: u64) -> String {
+  // Days-to-civil conversion from Howard Hinnant's date algorithms
+  let days = (secs / 86400) as i64 + 719_468;
+  let era = days.div_euclid(146_097);
+  let day_of_era = days.rem_euclid(146_097);
+  let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+  let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+  let month_index = (5 * day_of_year + 2) / 153;
+  let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+  let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+  let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+  format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn temp_path_for(path: &Path) -> PathBuf {
+  let mut temp = path.as_os_str().to_owned();
+  temp.push(TEMP_FILE_SUFFIX);
+  PathBuf::from(temp)
+}
+
+/// Where the upload pass looks up the remote copies of changed files. A full scan
+/// lists the prefix once, following every page, instead of asking about each file;
+/// a watcher run touches few enough files that asking about each is cheaper.
+enum RemoteIndex {
+  Listed(HashMap<String, RemoteObject>),
+  PerKey,
+}
+
+impl RemoteIndex {
+  async fn new(backend: &dyn StorageBackend, prefix: &str, scope_keys: Option<&[String]>, files_to_check: usize) -> Result<Self> {
+      if scope_keys.is_some() || files_to_check == 0 {
+          return Ok(RemoteIndex::PerKey);
+      }
+      let objects = backend.list(prefix).await?;
+      Ok(RemoteIndex::Listed(objects.into_iter().map(|object| (object.key.clone(), object)).collect()))
+  }
+
+  /// Finds the remote copy of a local file that changed since `synced_e_tag`, if
+  /// it had been synced before. Listings don't always carry user metadata (S3's never
+  /// do), so a listed object without it is judged by its ETag where that settles it,
+  /// and only fetched again to get the metadata where it doesn't.
+  async fn find(&self, backend: &dyn StorageBackend, remote_key: &str, local: &LocalFile, synced_e_tag: Option<&str>) -> Result<RemoteCopy> {
+      let found = |remote: Option<RemoteObject>| remote.map_or(RemoteCopy::Stale, RemoteCopy::Found);
+      let RemoteIndex::Listed(objects) = self else { return Ok(found(backend.stat(remote_key).await?)) };
+      let Some(listed) = objects.get(remote_key) else { return Ok(RemoteCopy::Stale) };
+      if listed.size != local.size {
+          // Can't hold the same content
+          return Ok(RemoteCopy::Stale);
+      }
+      if !listed.metadata.is_empty() {
+          return Ok(RemoteCopy::Found(listed.clone()));
+      }
+      if listed.e_tag.is_some() && listed.e_tag.as_deref() == synced_e_tag {
+          // Still what was synced before the file changed
+          return Ok(RemoteCopy::Stale);
+      }
+      if let Some(md5) = listed.e_tag.as_deref().and_then(plain_md5) {
+          if md5_file(Path::new(&local.path)).is_ok_and(|local_md5| local_md5 == md5) {
+              return Ok(RemoteCopy::Current(listed.clone()));
+          }
+      }
+      Ok(found(backend.stat(remote_key).await?))
+  }
+}
+
+/// What `RemoteIndex::find` found out about a file's remote copy.
+enum RemoteCopy {
+  /// There is none, or it doesn't hold the file's content.
+  Stale,
+  /// Holds the file's content, going by its ETag.
+  Current(RemoteObject),
+  /// Whether it holds the file's content is up to its metadata.
+  Found(RemoteObject),
+}
+
+/// The MD5 in an ETag that is one, as S3's are for objects uploaded in one piece.
+/// Multipart uploads (`<md5>-<parts>`) and other services' ETags aren't.
+fn plain_md5(e_tag: &str) -> Option<&str> {
+  let md5 = e_tag.trim_matches('"');
+  (md5.len() == 32 && md5.bytes().all(|byte| byte.is_ascii_hexdigit())).then_some(md5)
+}
+
+/// Whether the remote object already holds the local file's content. Objects uploaded
+/// by IceBucket carry the content hash; for anything else we fall back to the stored
+/// modification time and size, and otherwise assume the remote is stale.
+fn is_remote_current(remote: &RemoteObject, local: &LocalFile) -> bool {
+  if let Some(remote_hash) = remote.metadata.get(HASH_METADATA_KEY) {
+      return remote_hash == &local.hash;
+  }
+  match remote.metadata.get(MTIME_METADATA_KEY) {
+      Some(remote_mtime) => remote_mtime == &local.modified.to_string() && remote.size == local.size,
+      None => false,
+  }
+}
+
+fn save_state(state: &SyncState) {
+  if let Err(e) = state.save() {
+      eprintln!("Failed to save sync state for {}: {}", state.directory, e);
+  }
+}
+
+/// IceBucket's own files, which are never synced: any `sync.json` (it holds the
+/// secret key), in-progress downloads, and the settings, log and state files
+/// when the working directory is itself a synced folder.
+struct ControlFiles {
+  files: Vec<PathBuf>,
+  state_directory: Option<PathBuf>,
+}
+
+impl ControlFiles {
+  fn new(state: &SyncState) -> Self {
+      ControlFiles {
+          files: [SETTINGS_FILE, LOG_FILE].iter().filter_map(|file| fs::canonicalize(file).ok()).collect(),
+          state_directory: state.path().parent().and_then(|dir| fs::canonicalize(dir).ok()),
+      }
+  }
+
+  fn is_state_directory(&self, canonical_dir: &Path) -> bool {
+      self.state_directory.as_deref() == Some(canonical_dir)
+  }
+
+  /// Whether `path` is in the state directory. The path may already be gone, so the
+  /// nearest folder of it that still exists is resolved instead.
+  fn is_in_state_directory(&self, path: &Path) -> bool {
+      let Some(state_directory) = &self.state_directory else { return false };
+      path.ancestors()
+          .find_map(|ancestor| fs::canonicalize(ancestor).ok())
+          .is_some_and(|canonical| canonical.starts_with(state_directory))
+  }
+
+  fn contains(&self, path: &Path) -> bool {
+      let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+      if name.ends_with(TEMP_FILE_SUFFIX) {
+          return true;
+      }
+      // Only the handful of names that could be ours are worth resolving
+      if name != SETTINGS_FILE && name != LOG_FILE {
+          return false;
+      }
+      fs::canonicalize(path).map(|canonical| self.files.contains(&canonical)).unwrap_or(false)
+  }
+}
+
+/// Keys that must never be uploaded from, or downloaded into, a synced folder. That
+/// covers a `sync.json` at any depth, as another synced folder may be nested inside.
+fn is_control_key(key: &str) -> bool {
+  let name = key.rsplit('/').next().unwrap_or(key);
+  name == SYNC_SETTINGS_FILE || name.ends_with(TEMP_FILE_SUFFIX)
+}
+
+/// Earlier versions uploaded each folder's `sync.json`, secret key included. Warns if
+/// a copy is still in the bucket and offers to delete it.
+pub async fn remove_exposed_sync_settings(dir: &str, engine: &Mutex<SyncEngine>) {
+  let sync_settings = engine.lock().await.sync_settings(dir);
+  let sync_settings = match sync_settings {
+      Ok(sync_settings) => sync_settings,
+      Err(e) => {
+          eprintln!("Couldn't check {} for an uploaded sync.json: {}", dir, e);
+          return;
+      }
+  };
+  let backend = match SyncEngine::backend(engine, &sync_settings).await {
+      Ok(backend) => backend,
+      Err(e) => {
+          eprintln!("Couldn't check {} for an uploaded sync.json: {}", dir, e);
+          return;
+      }
+  };
+  let remote_key = format!("{}{}", key_prefix(&sync_settings.prefix), SYNC_SETTINGS_FILE);
+  match backend.stat(&remote_key).await {
+      Ok(Some(_)) => {}
+      Ok(None) => return,
+      Err(e) => {
+          eprintln!("Couldn't check {} for an uploaded sync.json: {}", dir, e);
+          return;
+      }
+  }
+
+  eprintln!("Warning: {} in bucket {} is a copy of {}'s sync.json, including its secret key", remote_key, sync_settings.bucket, dir);
+  let question = format!(
+      "The bucket \"{}\" contains {}, a copy of the sync settings for {}. It includes your secret key{}.\n\nDelete it from the bucket now? You should also rotate the key.",
+      sync_settings.bucket,
+      remote_key,
+      dir,
+      if sync_settings.public { " and may be publicly readable" } else { "" },
+  );
+  if ask_yes_no("IceBucket - Secret key exposed", &question) {
+      match backend.delete(&remote_key).await {
+          Ok(()) => println!("Deleted {} from bucket {}", remote_key, sync_settings.bucket),
+          Err(e) => eprintln!("Failed to delete {} from bucket {}: {}", remote_key, sync_settings.bucket, e),
+      }
+  }
+}
+
+/// What a walk of a synced folder leaves out.
+struct WalkFilter<'a> {
+  root: &'a Path,
+  control_files: ControlFiles,
+  rules: &'a mut ExcludeRules,
+  /// Keys of the folders that couldn't be read.
+  unreadable: Vec<String>,
+}
+
+/// Walks `dir` recursively, collecting every file up to `max_depth` levels below the
+/// synced root. Directories are canonicalized before being entered so that symlinks
+/// or junctions pointing back up the tree can't send the walk into a loop.
+fn collect_files(dir: &Path, depth: usize, max_depth: usize, filter: &mut WalkFilter, visited: &mut HashSet<PathBuf>, files: &mut Vec<PathBuf>) {
+  match fs::canonicalize(dir) {
+      Ok(canonical) => {
+          if filter.control_files.is_state_directory(&canonical) {
+              return;
+          }
+          if !visited.insert(canonical) {
+              if VERBOSE.load(Ordering::Relaxed) {
+                  println!("Skipping already visited directory: {}", dir.display());
+              }
+              return;
+          }
+      }
+      Err(e) => {
+          eprintln!("Failed to resolve directory {}: {}", dir.display(), e);
+          filter.unreadable.push(relative_key(filter.root, dir));
+          return;
+      }
+  }
+
+  let entries = match fs::read_dir(dir) {
+      Ok(entries) => entries,
+      Err(e) => {
+          eprintln!("Failed to read directory {}: {}", dir.display(), e);
+          filter.unreadable.push(relative_key(filter.root, dir));
+          return;
+      }
+  };
+
+  for entry in entries {
+      let Ok(entry) = entry else {
+          // Whatever that entry was can't be told apart from a deleted file
+          filter.unreadable.push(relative_key(filter.root, dir));
+          continue;
+      };
+      let path = entry.path();
+      let key = relative_key(filter.root, &path);
+      if path.is_dir() {
+          if filter.rules.is_entry_excluded(&key, true) {
+              if VERBOSE.load(Ordering::Relaxed) {
+                  println!("Excluded, skipping: {}", path.display());
+              }
+          } else if depth < max_depth {
+              collect_files(&path, depth + 1, max_depth, filter, visited, files);
+          } else if VERBOSE.load(Ordering::Relaxed) {
+              println!("Max depth reached, skipping: {}", path.display());
+          }
+      } else if path.is_file() && !filter.control_files.contains(&path) && !filter.rules.is_entry_excluded(&key, false) {
+          files.push(path);
+      }
+  }
+}
+
+/// Maps a file below `root` to its object key, e.g. `photos/2025/a.jpg`.
+pub fn relative_key(root: &Path, file: &Path) -> String {
+  let relative = file.strip_prefix(root).unwrap_or(file);
+  relative
+      .components()
+      .filter_map(|component| match component {
+          std::path::Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+          _ => None,
+      })
+      .collect::<Vec<String>>()
+      .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing::{FakeBackend, temp_dir};
+  use std::sync::atomic::AtomicUsize;
+
+  fn local_file(hash: &str) -> LocalFile {
+      LocalFile {
+          key: "a.txt".to_string(),
+          path: "a.txt".to_string(),
+          size: 1,
+          modified: 1,
+          hash: hash.to_string(),
+      }
+  }
+
+  fn remote_object(e_tag: &str) -> RemoteObject {
+      RemoteObject {
+          key: "a.txt".to_string(),
+          size: 1,
+          e_tag: Some(e_tag.to_string()),
+          version_id: None,
+          last_modified: None,
+          metadata: HashMap::new(),
+      }
+  }
+
+  fn base() -> FileState {
+      FileState {
+          hash: Some("hash".to_string()),
+          e_tag: Some("etag".to_string()),
+          ..FileState::default()
+      }
+  }
+
+  #[test]
+  fn two_way_step_follows_whichever_side_changed() {
+      let (same_local, edited_local) = (local_file("hash"), local_file("edited"));
+      let (same_remote, edited_remote) = (remote_object("etag"), remote_object("edited"));
+      let base = base();
+
+      assert!(matches!(two_way_step(Some(&same_local), Some(&same_remote), Some(&base), false), TwoWayStep::Unchanged));
+      assert!(matches!(two_way_step(Some(&edited_local), Some(&same_remote), Some(&base), false), TwoWayStep::Transfer(Transfer::Upload(_))));
+      assert!(matches!(two_way_step(Some(&same_local), Some(&edited_remote), Some(&base), false), TwoWayStep::Transfer(Transfer::Download(_))));
+      assert!(matches!(two_way_step(Some(&edited_local), Some(&edited_remote), Some(&base), false), TwoWayStep::Transfer(Transfer::Resolve(..))));
+      // Never synced: whichever side has it is new
+      assert!(matches!(two_way_step(Some(&same_local), None, None, false), TwoWayStep::Transfer(Transfer::Upload(_))));
+      assert!(matches!(two_way_step(None, Some(&same_remote), None, false), TwoWayStep::Transfer(Transfer::Download(_))));
+      assert!(matches!(two_way_step(Some(&same_local), Some(&same_remote), None, false), TwoWayStep::Transfer(Transfer::Resolve(..))));
+      // Synced without an ETag: the object can't be shown unchanged
+      let no_e_tag = FileState { e_tag: None, ..base.clone() };
+      assert!(matches!(two_way_step(Some(&same_local), Some(&same_remote), Some(&no_e_tag), false), TwoWayStep::Transfer(Transfer::Download(_))));
+  }
+
+  #[test]
+  fn two_way_step_carries_deletions_over() {
+      let (same_local, edited_local) = (local_file("hash"), local_file("edited"));
+      let (same_remote, edited_remote) = (remote_object("etag"), remote_object("edited"));
+      let base = base();
+
+      assert!(matches!(two_way_step(None, Some(&same_remote), Some(&base), false), TwoWayStep::Transfer(Transfer::DeleteRemote)));
+      assert!(matches!(two_way_step(Some(&same_local), None, Some(&base), false), TwoWayStep::DeleteLocal(_)));
+      assert!(matches!(two_way_step(None, None, Some(&base), false), TwoWayStep::Forget));
+      // An edit wins over a deletion on the other side
+      assert!(matches!(two_way_step(Some(&edited_local), None, Some(&base), false), TwoWayStep::Transfer(Transfer::Upload(_))));
+      assert!(matches!(two_way_step(None, Some(&edited_remote), Some(&base), false), TwoWayStep::Transfer(Transfer::Download(_))));
+  }
+
+  #[test]
+  fn two_way_step_keeps_local_files_when_the_remote_vanished() {
+      let (same_local, edited_local) = (local_file("hash"), local_file("edited"));
+      let base = base();
+
+      assert!(matches!(two_way_step(Some(&same_local), None, Some(&base), true), TwoWayStep::Keep));
+      assert!(matches!(two_way_step(Some(&edited_local), None, Some(&base), true), TwoWayStep::Transfer(Transfer::Upload(_))));
+  }
+
+  #[tokio::test]
+  async fn find_settles_listed_objects_by_e_tag_where_it_can() {
+      let dir = temp_dir("find");
+      let path = dir.join("a.txt");
+      fs::write(&path, "abc").unwrap();
+      let local = LocalFile { path: path.to_string_lossy().into_owned(), size: 3, ..local_file("hash") };
+      let md5 = "900150983cd24fb0d6963f7d28e17f72";
+      let listed = |e_tag: &str| RemoteObject { size: 3, ..remote_object(e_tag) };
+      let index = |object: RemoteObject| RemoteIndex::Listed(HashMap::from([(object.key.clone(), object)]));
+      let backend = FakeBackend::default();
+      let find = |index: RemoteIndex, synced_e_tag: Option<&'static str>| {
+          let (backend, local) = (&backend, &local);
+          async move { index.find(backend, "a.txt", local, synced_e_tag).await.unwrap() }
+      };
+
+      assert!(matches!(find(RemoteIndex::Listed(HashMap::new()), None).await, RemoteCopy::Stale));
+      assert!(matches!(find(index(remote_object(md5)), None).await, RemoteCopy::Stale));
+      assert!(matches!(find(index(listed("\"synced\"")), Some("\"synced\"")).await, RemoteCopy::Stale));
+      assert!(matches!(find(index(listed(&format!("\"{}\"", md5))), None).await, RemoteCopy::Current(_)));
+      let mut tagged = listed("other");
+      tagged.metadata.insert(HASH_METADATA_KEY.to_string(), "hash".to_string());
+      assert!(matches!(find(index(tagged), None).await, RemoteCopy::Found(_)));
+      assert_eq!(backend.stat_calls.load(Ordering::Relaxed), 0);
+
+      // Neither a multipart ETag nor another file's MD5 settles it
+      find(index(listed(&format!("\"{}-2\"", md5))), None).await;
+      find(index(listed("\"00000000000000000000000000000000\"")), Some("\"synced\"")).await;
+      assert_eq!(backend.stat_calls.load(Ordering::Relaxed), 2);
+      fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn run_transfers_lets_transfers_under_way_finish_after_a_failure() {
+      let started = AtomicUsize::new(0);
+      let started = &started;
+      let transfers = (0..5u64).map(|i| async move {
+          started.fetch_add(1, Ordering::Relaxed);
+          tokio::time::sleep(Duration::from_millis(10 * (i + 1))).await;
+          i
+      }).collect();
+      let mut settled = Vec::new();
+      let result = run_transfers(transfers, 2, |i| {
+          settled.push(i);
+          match i {
+              0 => Err(IceBucketError::Auth("Uploading a.txt".to_string())),
+              _ => Ok(()),
+          }
+      }).await;
+
+      assert!(matches!(result, Err(IceBucketError::Auth(_))));
+      assert_eq!(settled, [0, 1]);
+      assert_eq!(started.load(Ordering::Relaxed), 2);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn run_transfers_keeps_to_the_limit() {
+      let (running, most_running) = (AtomicUsize::new(0), AtomicUsize::new(0));
+      let (running, most_running) = (&running, &most_running);
+      let transfers = (0..10u64).map(|i| async move {
+          most_running.fetch_max(running.fetch_add(1, Ordering::Relaxed) + 1, Ordering::Relaxed);
+          tokio::time::sleep(Duration::from_millis(i % 3 + 1)).await;
+          running.fetch_sub(1, Ordering::Relaxed);
+      }).collect();
+      let mut settled = 0;
+      run_transfers(transfers, 3, |()| {
+          settled += 1;
+          Ok(())
+      }).await.unwrap();
+
+      assert_eq!(settled, 10);
+      assert_eq!(most_running.load(Ordering::Relaxed), 3);
+  }
+
+  #[test]
+  fn plain_md5_skips_multipart_e_tags() {
+      assert_eq!(plain_md5("\"900150983cd24fb0d6963f7d28e17f72\""), Some("900150983cd24fb0d6963f7d28e17f72"));
+      assert_eq!(plain_md5("900150983cd24fb0d6963f7d28e17f72"), Some("900150983cd24fb0d6963f7d28e17f72"));
+      assert_eq!(plain_md5("\"900150983cd24fb0d6963f7d28e17f72-3\""), None);
+      assert_eq!(plain_md5("0x8DC0000000000000"), None);
+  }
+
+  #[test]
+  fn local_path_for_key_stays_inside_the_folder() {
+      assert_eq!(local_path_for_key("synced", "photos/a.jpg"), Some(Path::new("synced").join("photos").join("a.jpg")));
+      for key in ["../a.txt", "photos/../../a.txt", "./a.txt", "C:/Windows/a.txt", "photos/C:a.txt", "photos\\..\\a.txt", "photos//a.txt", "/a.txt", ""] {
+          assert_eq!(local_path_for_key("synced", key), None, "{:?}", key);
+      }
+  }
+
+  #[test]
+  fn control_keys_are_recognised_in_subfolders() {
+      assert!(is_control_key(SYNC_SETTINGS_FILE));
+      assert!(is_control_key(&format!("nested/{}", SYNC_SETTINGS_FILE)));
+      assert!(is_control_key(&format!("a.txt{}", TEMP_FILE_SUFFIX)));
+      assert!(!is_control_key(&format!("{}.bak", SYNC_SETTINGS_FILE)));
+      assert!(!is_control_key("photos/a.jpg"));
+  }
+
+  #[test]
+  fn format_date_handles_leap_years() {
+      assert_eq!(format_date(0), "1970-01-01");
+      assert_eq!(format_date(951_782_400), "2000-02-29");
+      assert_eq!(format_date(1_709_251_199), "2024-02-29");
+      assert_eq!(format_date(1_735_689_600), "2025-01-01");
+  }
+
+  #[test]
+  fn conflict_path_for_numbers_taken_names() {
+      let dir = temp_dir("conflict-path");
+      let date = format_date(now_secs());
+
+      let first = conflict_path_for(&dir.join("report.txt"));
+      let name = first.file_name().unwrap().to_string_lossy().to_string();
+      assert!(name.starts_with("report (conflict from "), "{}", name);
+      assert!(name.ends_with(&format!(" {}).txt", date)), "{}", name);
+      assert_eq!(first.parent(), Some(dir.as_path()));
+
+      fs::write(&first, "").unwrap();
+      let second = conflict_path_for(&dir.join("report.txt"));
+      assert!(second.to_string_lossy().ends_with(&format!(" {} 2).txt", date)), "{}", second.display());
+
+      let no_extension = conflict_path_for(&dir.join("Makefile"));
+      assert!(no_extension.to_string_lossy().ends_with(&format!(" {})", date)), "{}", no_extension.display());
   }
-}
\ No newline at end of file
+}
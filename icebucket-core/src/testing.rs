@@ -0,0 +1,88 @@
+// Helpers shared by the unit tests.
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::error::{IceBucketError, Result};
+use crate::services::{RemoteObject, StorageBackend, UploadedObject};
+use crate::{Log, SyncSettings};
+
+static DIR_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Creates an empty folder in the system temp folder that no other test uses.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let n = DIR_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let dir = env::temp_dir().join(format!("icebucket-test-{}-{}-{}", process::id(), name, n));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Settings for a folder synced to `bucket` on `service`, with everything optional
+/// left at its default.
+pub fn sync_settings(service: &str, bucket: &str) -> SyncSettings {
+    serde_json::from_value(serde_json::json!({
+        "service": service,
+        "access_key": "",
+        "secret_key": "",
+        "region": "",
+        "bucket": bucket,
+        "endpoint": "",
+        "sync_type": "upload-only",
+        "conflicts": "keep-local",
+    })).unwrap()
+}
+
+/// A backend that only answers `stat`, from `objects`, after failing with each of
+/// `stat_failures` in turn. It counts the calls so tests can tell which requests a
+/// sync would have made.
+#[derive(Default)]
+pub struct FakeBackend {
+    pub objects: HashMap<String, RemoteObject>,
+    pub stat_failures: Mutex<Vec<IceBucketError>>,
+    pub stat_calls: Arc<AtomicU32>,
+}
+
+#[async_trait]
+impl StorageBackend for FakeBackend {
+    fn name(&self) -> &'static str {
+        "Fake"
+    }
+
+    async fn list(&self, _prefix: &str) -> Result<Vec<RemoteObject>> {
+        unimplemented!()
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<RemoteObject>> {
+        self.stat_calls.fetch_add(1, Ordering::Relaxed);
+        if let Some(failure) = self.stat_failures.lock().unwrap().pop() {
+            return Err(failure);
+        }
+        Ok(self.objects.get(key).cloned())
+    }
+
+    async fn put(&self, _key: &str, _file_path: &str, _metadata: &HashMap<String, String>, _log: &Log) -> Result<UploadedObject> {
+        unimplemented!()
+    }
+
+    async fn get(&self, _key: &str, _destination: &Path, _temp_path: &Path, _log: &Log) -> Result<RemoteObject> {
+        unimplemented!()
+    }
+
+    async fn delete(&self, _key: &str) -> Result<()> {
+        unimplemented!()
+    }
+
+    async fn copy(&self, _from: &str, _to: &str) -> Result<()> {
+        unimplemented!()
+    }
+
+    async fn set_metadata(&self, _key: &str, _metadata: &HashMap<String, String>) -> Result<()> {
+        unimplemented!()
+    }
+}
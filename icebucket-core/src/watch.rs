@@ -0,0 +1,179 @@
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{self, Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest a batch keeps growing while a folder never goes quiet (a large copy in
+/// progress, a log being appended to), so those changes still get synced.
+const MAX_BATCH_WAIT: Duration = Duration::from_secs(30);
+
+/// Watches the synced folders for filesystem events, so changes can be synced within
+/// moments instead of at the next scan.
+pub struct DirectoryWatcher {
+    // Events stop when the watcher is dropped
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    /// Each watched folder as configured, along with the paths events may report it under.
+    directories: Vec<(String, Vec<PathBuf>)>,
+}
+
+impl DirectoryWatcher {
+    /// Starts watching every folder recursively. Returns `None` if the platform watcher
+    /// can't be created, in which case the caller falls back to periodic scans.
+    pub fn new(directories: &[String]) -> Option<Self> {
+        let (sender, events) = channel();
+        let mut watcher = match notify::recommended_watcher(sender) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                eprintln!("Failed to start watching for changes: {}", e);
+                return None;
+            }
+        };
+
+        let mut watched = Vec::new();
+        for dir in directories {
+            match watcher.watch(Path::new(dir), RecursiveMode::Recursive) {
+                Ok(()) => watched.push((dir.clone(), event_roots(dir))),
+                Err(e) => eprintln!("Failed to watch {} for changes: {}", dir, e),
+            }
+        }
+
+        Some(DirectoryWatcher {
+            _watcher: watcher,
+            events,
+            directories: watched,
+        })
+    }
+
+    /// Blocks until something changes or `deadline` passes. Once a change arrives, keeps
+    /// collecting until nothing has changed for `quiet_period`, so an editor's save or a
+    /// folder being copied in becomes a single batch. Returns the changed paths (below
+    /// each folder's configured path) by folder; a folder whose events overflowed maps
+    /// to its own root, meaning it needs a full scan.
+    pub fn wait_for_changes(&self, deadline: Instant, quiet_period: Duration) -> HashMap<String, HashSet<PathBuf>> {
+        let mut changes = HashMap::new();
+        match self.events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(event) => self.add_event(event, &mut changes),
+            Err(RecvTimeoutError::Timeout) => return changes,
+            Err(RecvTimeoutError::Disconnected) => {
+                // The watcher is gone; sleep out the wait rather than spin
+                thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                return changes;
+            }
+        }
+
+        let batch_deadline = Instant::now() + MAX_BATCH_WAIT;
+        loop {
+            let wait = quiet_period.min(batch_deadline.saturating_duration_since(Instant::now()));
+            if wait.is_zero() {
+                break;
+            }
+            match self.events.recv_timeout(wait) {
+                Ok(event) => self.add_event(event, &mut changes),
+                Err(_) => break,
+            }
+        }
+        changes
+    }
+
+    fn add_event(&self, event: notify::Result<Event>, changes: &mut HashMap<String, HashSet<PathBuf>>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                eprintln!("Error watching for changes: {}", e);
+                return;
+            }
+        };
+        if event.need_rescan() {
+            for (dir, _) in &self.directories {
+                changes.entry(dir.clone()).or_default().insert(PathBuf::from(dir));
+            }
+            return;
+        }
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+        for path in &event.paths {
+            for (dir, path) in locate(&self.directories, path) {
+                changes.entry(dir).or_default().insert(path);
+            }
+        }
+    }
+}
+
+/// The paths events for `dir` may start with. Watchers report absolute paths even for
+/// a relative folder such as the default `./`: on Windows the working directory joined
+/// with the folder as given, elsewhere often the canonical path, which on Windows has
+/// a `\\?\` prefix that never matches the others.
+fn event_roots(dir: &str) -> Vec<PathBuf> {
+    let mut roots = vec![PathBuf::from(dir)];
+    let candidates = [
+        env::current_dir().map(|current| current.join(dir)).ok(),
+        path::absolute(dir).ok(),
+        fs::canonicalize(dir).ok(),
+    ];
+    for root in candidates.into_iter().flatten() {
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+    }
+    roots
+}
+
+/// Finds the folders an event path belongs to (more than one if synced folders are
+/// nested) and re-expresses it below each folder's configured path.
+fn locate(directories: &[(String, Vec<PathBuf>)], path: &Path) -> Vec<(String, PathBuf)> {
+    let mut found = Vec::new();
+    for (dir, roots) in directories {
+        if let Some(relative) = roots.iter().find_map(|root| path.strip_prefix(root).ok()) {
+            found.push((dir.clone(), Path::new(dir).join(relative)));
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn locate_matches_absolute_events_for_relative_folders() {
+        let directories = vec![
+            ("./".to_string(), event_roots("./")),
+            ("photos".to_string(), event_roots("photos")),
+        ];
+        let current = env::current_dir().unwrap();
+
+        // The way the Windows watcher builds event paths
+        let found = locate(&directories, &current.join("./").join("a.txt"));
+        assert_eq!(found, vec![("./".to_string(), Path::new("./").join("a.txt"))]);
+
+        let mut found = locate(&directories, &current.join("photos").join("2025").join("b.jpg"));
+        found.sort();
+        assert_eq!(found, vec![
+            ("./".to_string(), Path::new("./").join("photos").join("2025").join("b.jpg")),
+            ("photos".to_string(), Path::new("photos").join("2025").join("b.jpg")),
+        ]);
+
+        assert!(locate(&directories, &current.parent().unwrap().join("elsewhere.txt")).is_empty());
+    }
+
+    #[test]
+    fn watches_a_relative_folder() {
+        let dir = format!("icebucket-test-watch-{}", process::id());
+        fs::create_dir_all(&dir).unwrap();
+        let watcher = DirectoryWatcher::new(std::slice::from_ref(&dir)).unwrap();
+
+        fs::write(Path::new(&dir).join("a.txt"), "hello").unwrap();
+        let changes = watcher.wait_for_changes(Instant::now() + Duration::from_secs(10), Duration::from_millis(200));
+        drop(watcher);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(changes.get(&dir).is_some_and(|paths| paths.contains(&Path::new(&dir).join("a.txt"))), "{:?}", changes);
+    }
+}
@@ -12,6 +12,9 @@ const SETTINGS_FILE: &str = "settings.json";
 struct SettingsData {
     directories_to_scan: Vec<String>,
     seconds_between_scans: u64,
+    /// Settings the GUI doesn't edit (e.g. `state_directory`), kept so saving doesn't drop them.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Default for SettingsData {
@@ -19,6 +22,7 @@ impl Default for SettingsData {
         Self {
             directories_to_scan: vec![],
             seconds_between_scans: 60,
+            extra: serde_json::Map::new(),
         }
     }
 }
@@ -33,6 +37,9 @@ struct SyncSettings {
     service: String,
     sync_type: String,
     conflicts: String,
+    /// Settings the GUI doesn't edit (e.g. `public`, `max_depth`), kept so saving doesn't drop them.
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Default for SyncSettings {
@@ -46,6 +53,7 @@ impl Default for SyncSettings {
             service: "s3".to_string(),
             sync_type: "upload-only".to_string(),
             conflicts: "keep-local".to_string(),
+            extra: serde_json::Map::new(),
         }
     }
 }
@@ -105,9 +113,9 @@ impl IceBucketGui {
     }
 
     fn view_sync_settings(&self) -> Element<Message> {
-        let services = vec!["s3".to_string(), "other".to_string()];
+        let services = vec!["s3".to_string(), "local".to_string(), "sftp".to_string(), "ftp".to_string(), "azure".to_string(), "gcs".to_string(), "webdav".to_string(), "other".to_string()];
         let sync_types = vec!["upload-only".to_string(), "download-only".to_string(), "sync".to_string()];
-        let conflicts = vec!["keep-local".to_string(), "keep-remote".to_string()];
+        let conflicts = vec!["keep-local".to_string(), "use-remote".to_string(), "keep-both".to_string()];
 
         container(
             column![